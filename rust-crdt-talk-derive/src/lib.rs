@@ -0,0 +1,59 @@
+//! `#[derive(Merge)]` for structs made of CRDTs. Use it through
+//! `rust_crdt_talk::crdt::Merge`, which re-exports it next to the trait.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Index, Member, parse_macro_input};
+
+/// Merge a struct by merging each of its fields, in a span named like
+/// `Task::merge_mut`. Every field has to be `Merge` itself; if one isn't, the
+/// error points at that field.
+#[proc_macro_derive(Merge)]
+pub fn derive_merge(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let Data::Struct(data) = &input.data else {
+        return syn::Error::new_spanned(
+            &input.ident,
+            "Merge can only be derived for structs; merge enums and unions by hand",
+        )
+        .to_compile_error()
+        .into();
+    };
+
+    // Structs without fields have nothing to merge, and shouldn't warn about
+    // the unused argument.
+    let other = if data.fields.is_empty() {
+        format_ident!("_other")
+    } else {
+        format_ident!("other")
+    };
+
+    let merges = data.fields.iter().enumerate().map(|(index, field)| {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(index)),
+        };
+        let ty = &field.ty;
+
+        quote_spanned! {ty.span()=>
+            <#ty as ::rust_crdt_talk::crdt::Merge>::merge_mut(&mut self.#member, #other.#member);
+        }
+    });
+
+    let name = &input.ident;
+    let span_name = format!("{name}::merge_mut");
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    quote! {
+        impl #impl_generics ::rust_crdt_talk::crdt::Merge for #name #ty_generics #where_clause {
+            fn merge_mut(&mut self, #other: Self) {
+                let _span = ::rust_crdt_talk::crdt::merge::tracing::info_span!(#span_name).entered();
+
+                #(#merges)*
+            }
+        }
+    }
+    .into()
+}
@@ -1,3 +1,15 @@
+pub mod clocked;
+pub use clocked::Clocked;
+
+pub mod counter;
+pub use counter::{GCounter, PNCounter};
+
+pub mod delta;
+pub use delta::Delta;
+
+pub mod flag;
+pub use flag::{DWFlag, EWFlag};
+
 pub mod gmap;
 
 pub mod gset;
@@ -13,8 +25,32 @@ pub mod lwwset;
 pub mod merge;
 pub use merge::Merge;
 
+pub mod mvregister;
+pub use mvregister::MVRegister;
+
+pub mod node_table;
+pub use node_table::NodeTable;
+
+pub mod ormap;
+pub use ormap::ORMap;
+
+pub mod rga;
+pub use rga::{ElementId, RGA};
+
+pub mod text;
+pub use text::Text;
+
+pub mod tree;
+pub use tree::{Parent, Tree};
+
 pub mod twopmap;
 pub use twopmap::TwoPMap;
 
+pub mod unknown;
+pub use unknown::{Unknown, UnknownFields};
+
+pub mod version_vector;
+pub use version_vector::VersionVector;
+
 #[cfg(test)]
 pub mod max;
@@ -0,0 +1,20 @@
+use super::HybridLogicalClock;
+
+/// Types that carry hybrid logical clocks, so a replica can see every clock in
+/// data it receives.
+pub trait Clocked {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock));
+
+    fn max_clock(&self) -> Option<HybridLogicalClock> {
+        let mut max = None;
+        self.each_clock(&mut |clock| max = max.max(Some(*clock)));
+
+        max
+    }
+}
+
+impl Clocked for HybridLogicalClock {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        f(self)
+    }
+}
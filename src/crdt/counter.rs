@@ -0,0 +1,125 @@
+use super::Merge;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A counter that only goes up. Each replica counts its own increments, so
+/// increments made concurrently on different replicas all survive the merge.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
+pub struct GCounter(
+    #[cfg_attr(
+        any(test, feature = "testing"),
+        proptest(strategy = "counts_strategy()")
+    )]
+    BTreeMap<Uuid, u64>,
+);
+
+#[cfg(any(test, feature = "testing"))]
+fn counts_strategy() -> impl proptest::strategy::Strategy<Value = BTreeMap<Uuid, u64>> {
+    use proptest::prelude::*;
+
+    proptest::collection::btree_map((0..=2u128).prop_map(Uuid::from_u128), 0..10u64, 0..3)
+}
+
+impl GCounter {
+    #[tracing::instrument(name = "GCounter::increment", skip(self))]
+    pub fn increment(&mut self, node_id: Uuid, by: u64) {
+        let count = self.0.entry(node_id).or_default();
+        *count = count.saturating_add(by);
+    }
+
+    /// The total, stopping at `u64::MAX` instead of overflowing.
+    pub fn value(&self) -> u64 {
+        self.0
+            .values()
+            .fold(0, |total: u64, count| total.saturating_add(*count))
+    }
+}
+
+impl Merge for GCounter {
+    #[tracing::instrument(name = "GCounter::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        for (node_id, count) in other.0 {
+            let ours = self.0.entry(node_id).or_default();
+            *ours = count.max(*ours);
+        }
+    }
+}
+
+/// A counter that can go up and down, made of one `GCounter` for increments
+/// and another for decrements.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Merge, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    #[tracing::instrument(name = "PNCounter::increment", skip(self))]
+    pub fn increment(&mut self, node_id: Uuid, by: u64) {
+        self.increments.increment(node_id, by);
+    }
+
+    #[tracing::instrument(name = "PNCounter::decrement", skip(self))]
+    pub fn decrement(&mut self, node_id: Uuid, by: u64) {
+        self.decrements.increment(node_id, by);
+    }
+
+    /// Increments minus decrements, clamped to the range of an `i64`.
+    pub fn value(&self) -> i64 {
+        let value = i128::from(self.increments.value()) - i128::from(self.decrements.value());
+
+        i64::try_from(value).unwrap_or(if value > 0 { i64::MAX } else { i64::MIN })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(g_laws, GCounter, default);
+    crate::merge_laws!(pn_laws, PNCounter, default);
+
+    proptest! {
+        #[test]
+        fn concurrent_increments_add_up(base: PNCounter, a in 0..10u64, b in 0..10u64) {
+            let mut phone = base.clone();
+            phone.increment(Uuid::from_u128(1), a);
+
+            let mut computer = base.clone();
+            computer.increment(Uuid::from_u128(2), b);
+            computer.decrement(Uuid::from_u128(2), 1);
+
+            assert_eq!(phone.merge(computer).value(), base.value() + a as i64 + b as i64 - 1);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn huge_counts_saturate(
+            up in (u64::MAX - 10)..=u64::MAX,
+            down in (u64::MAX - 10)..=u64::MAX,
+            by in (u64::MAX - 10)..=u64::MAX,
+        ) {
+            let mut counter = PNCounter::default();
+            counter.increment(Uuid::from_u128(1), up);
+            counter.increment(Uuid::from_u128(1), by);
+            counter.increment(Uuid::from_u128(2), up);
+            assert_eq!(counter.increments.value(), u64::MAX);
+            assert_eq!(counter.value(), i64::MAX);
+
+            counter.decrement(Uuid::from_u128(1), down);
+            counter.decrement(Uuid::from_u128(2), down);
+            counter.decrement(Uuid::from_u128(3), down);
+            assert_eq!(counter.value(), 0);
+
+            let mut counter = PNCounter::default();
+            counter.decrement(Uuid::from_u128(1), down);
+            assert_eq!(counter.value(), i64::MIN);
+            counter.increment(Uuid::from_u128(1), up);
+            assert_eq!(i128::from(counter.value()), i128::from(up) - i128::from(down));
+        }
+    }
+}
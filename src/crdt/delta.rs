@@ -0,0 +1,43 @@
+use super::{Clocked, Merge, VersionVector};
+
+/// CRDTs that can tell another replica what it's missing without sending the
+/// whole state. A delta is a value of the same type, so deltas merge with each
+/// other (and with full states) under the same laws.
+pub trait Delta: Merge + Clocked + Sized {
+    /// The part of this state carrying clocks `seen` doesn't cover, or `None`
+    /// if there is nothing new.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self>;
+}
+
+/// Test that a delta from an empty context is the whole state (in other words,
+/// a replica that has seen nothing gets everything.)
+#[cfg(any(test, feature = "testing"))]
+pub fn test_delta_from_nothing<T>(v: T)
+where
+    T: Delta + PartialEq + std::fmt::Debug,
+{
+    match v.delta_since(&VersionVector::default()) {
+        Some(delta) => assert_eq!(delta, v),
+        None => assert_eq!(v.max_clock(), None),
+    }
+}
+
+/// Test that a delta is part of the state it came from (so merging it back in
+/// changes nothing) and that it only exists if it has something new to say.
+#[cfg(any(test, feature = "testing"))]
+pub fn test_delta_is_contained<T>(v: T, seen: VersionVector)
+where
+    T: Delta + Clone + PartialEq + std::fmt::Debug,
+{
+    let Some(delta) = v.delta_since(&seen) else {
+        assert!(seen.covers(&v), "no delta for {v:?} since {seen:?}");
+        return;
+    };
+
+    assert!(
+        !seen.covers(&delta),
+        "{delta:?} has nothing new since {seen:?}"
+    );
+
+    assert_eq!(v.clone().merge(delta), v);
+}
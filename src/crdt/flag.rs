@@ -0,0 +1,317 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use std::collections::BTreeSet;
+
+#[cfg(any(test, feature = "testing"))]
+use proptest::arbitrary::{Arbitrary, StrategyFor};
+
+/// The clocks of the operations holding a flag away from its resting state,
+/// plus every clock the flag has seen. Clearing only drops dots this replica
+/// has seen, so a dot added concurrently on another replica survives the
+/// merge. That's what makes one of the two operations win.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Dots {
+    dots: BTreeSet<HybridLogicalClock>,
+    context: VersionVector,
+}
+
+impl Dots {
+    fn add(&mut self, clock: HybridLogicalClock) {
+        self.dots.clear();
+        self.dots.insert(clock);
+        self.context.observe(clock);
+    }
+
+    fn clear(&mut self, clock: HybridLogicalClock) {
+        self.dots.clear();
+        self.context.observe(clock);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.dots.is_empty()
+    }
+}
+
+impl Merge for Dots {
+    fn merge_mut(&mut self, other: Self) {
+        // Like MVRegister: a dot survives if both sides have it, or if the
+        // other side has never seen it (and so can't have cleared it.)
+        let ours = std::mem::take(&mut self.dots);
+        let theirs = other.dots;
+
+        self.dots = ours
+            .iter()
+            .filter(|dot| theirs.contains(dot) || !other.context.contains(dot))
+            .chain(
+                theirs
+                    .iter()
+                    .filter(|dot| ours.contains(dot) || !self.context.contains(dot)),
+            )
+            .copied()
+            .collect();
+        self.context.merge_mut(other.context);
+    }
+}
+
+impl Clocked for Dots {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.dots.iter().for_each(&mut *f);
+        self.context.each_clock(f);
+    }
+}
+
+/// Runs of operations on different replicas (true adds a dot, false clears),
+/// merged together.
+#[cfg(any(test, feature = "testing"))]
+type Runs = Vec<Vec<(bool, HybridLogicalClock)>>;
+
+#[cfg(any(test, feature = "testing"))]
+fn runs_strategy() -> StrategyFor<Runs> {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    vec(vec(any::<(bool, HybridLogicalClock)>(), 0..3), 1..3)
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Dots {
+    fn from_runs(replicas: Runs) -> Self {
+        replicas
+            .into_iter()
+            .map(|mut ops| {
+                // Each run happens on one replica, so its clocks only go up.
+                ops.sort_by_key(|(_, clock)| *clock);
+
+                let mut dots = Self::default();
+                for (add, clock) in ops {
+                    if add {
+                        dots.add(clock);
+                    } else {
+                        dots.clear(clock);
+                    }
+                }
+
+                dots
+            })
+            .reduce(|mut a, b| {
+                a.merge_mut(b);
+                a
+            })
+            .unwrap()
+    }
+}
+
+/// A flag where enabling wins: if one replica enables the flag while another
+/// concurrently disables it, the merged flag is enabled. An operation that
+/// has seen the other one still overrides it as usual. Starts disabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EWFlag(Dots);
+
+impl EWFlag {
+    #[tracing::instrument(name = "EWFlag::new", skip(clock))]
+    pub fn new(value: bool, clock: HybridLogicalClock) -> Self {
+        let mut flag = Self::default();
+        flag.set(value, clock);
+
+        flag
+    }
+
+    #[tracing::instrument(name = "EWFlag::set", skip(self, clock))]
+    pub fn set(&mut self, value: bool, clock: HybridLogicalClock) {
+        if value {
+            self.0.add(clock);
+        } else {
+            self.0.clear(clock);
+        }
+    }
+
+    pub fn enable(&mut self, clock: HybridLogicalClock) {
+        self.set(true, clock);
+    }
+
+    pub fn disable(&mut self, clock: HybridLogicalClock) {
+        self.set(false, clock);
+    }
+
+    pub fn value(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl Merge for EWFlag {
+    #[tracing::instrument(name = "EWFlag::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        self.0.merge_mut(other.0);
+    }
+}
+
+impl Clocked for EWFlag {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.each_clock(f);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Arbitrary for EWFlag {
+    type Parameters = ();
+
+    type Strategy = proptest::strategy::Map<StrategyFor<Runs>, fn(Runs) -> Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        proptest::strategy::Strategy::prop_map(runs_strategy(), |runs| {
+            EWFlag(Dots::from_runs(runs))
+        })
+    }
+}
+
+impl Delta for EWFlag {
+    /// The whole flag if anything is new, since the context has to travel
+    /// with the dots for the merge to drop the ones that were cleared.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        (!seen.covers(self)).then(|| self.clone())
+    }
+}
+
+/// A flag where disabling wins: if one replica disables the flag while
+/// another concurrently enables it, the merged flag is disabled. An operation
+/// that has seen the other one still overrides it as usual.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DWFlag(Dots);
+
+impl DWFlag {
+    #[tracing::instrument(name = "DWFlag::new", skip(clock))]
+    pub fn new(value: bool, clock: HybridLogicalClock) -> Self {
+        let mut flag = Self(Dots::default());
+        flag.set(value, clock);
+
+        flag
+    }
+
+    #[tracing::instrument(name = "DWFlag::set", skip(self, clock))]
+    pub fn set(&mut self, value: bool, clock: HybridLogicalClock) {
+        if value {
+            self.0.clear(clock);
+        } else {
+            self.0.add(clock);
+        }
+    }
+
+    pub fn enable(&mut self, clock: HybridLogicalClock) {
+        self.set(true, clock);
+    }
+
+    pub fn disable(&mut self, clock: HybridLogicalClock) {
+        self.set(false, clock);
+    }
+
+    pub fn value(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Merge for DWFlag {
+    #[tracing::instrument(name = "DWFlag::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        self.0.merge_mut(other.0);
+    }
+}
+
+impl Clocked for DWFlag {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.each_clock(f);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Arbitrary for DWFlag {
+    type Parameters = ();
+
+    type Strategy = proptest::strategy::Map<StrategyFor<Runs>, fn(Runs) -> Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        proptest::strategy::Strategy::prop_map(runs_strategy(), |runs| {
+            DWFlag(Dots::from_runs(runs))
+        })
+    }
+}
+
+impl Delta for DWFlag {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        (!seen.covers(self)).then(|| self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::delta;
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(ew_laws, EWFlag, default);
+    crate::merge_laws!(dw_laws, DWFlag);
+
+    /// A clock older than any the strategies make, so the concurrent tests
+    /// don't have to throw most of their cases away.
+    fn before_everything() -> HybridLogicalClock {
+        use super::super::hlc::ManualClock;
+        use chrono::{TimeZone, Utc};
+
+        let time = ManualClock::new(Utc.timestamp_opt(-1, 0).unwrap());
+        HybridLogicalClock::new(uuid::Uuid::nil(), &time)
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_enable_wins(on: HybridLogicalClock, off: HybridLogicalClock) {
+            prop_assume!(on.node_id() != off.node_id());
+            let start = before_everything();
+
+            let base = EWFlag::new(false, start);
+            let mut enabled = base.clone();
+            enabled.enable(on);
+            let mut disabled = base;
+            disabled.disable(off);
+
+            assert!(enabled.merge(disabled).value());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_disable_wins(on: HybridLogicalClock, off: HybridLogicalClock) {
+            prop_assume!(on.node_id() != off.node_id());
+            let start = before_everything();
+
+            let base = DWFlag::new(true, start);
+            let mut enabled = base.clone();
+            enabled.enable(on);
+            let mut disabled = base;
+            disabled.disable(off);
+
+            assert!(!enabled.merge(disabled).value());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn later_operations_override(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first < second);
+
+            let mut ew = EWFlag::new(true, first);
+            let stale = ew.clone();
+            ew.disable(second);
+            assert!(!ew.merge(stale).value());
+
+            let mut dw = DWFlag::new(false, first);
+            let stale = dw.clone();
+            dw.enable(second);
+            assert!(dw.merge(stale).value());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: EWFlag, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
+}
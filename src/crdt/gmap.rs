@@ -1,9 +1,10 @@
 use super::merge::Merge;
+use super::{Clocked, Delta, HybridLogicalClock, VersionVector};
 use std::collections::{BTreeMap, btree_map::Entry};
 use std::hash::Hash;
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
 pub struct GMap<K: Hash + Ord, V: Merge>(BTreeMap<K, V>);
 
 impl<K: Hash + Ord, V: Merge> GMap<K, V> {
@@ -45,10 +46,28 @@ impl<K: Hash + Ord, V: Merge> Default for GMap<K, V> {
     }
 }
 
+impl<K: Hash + Ord, V: Merge + Clocked> Clocked for GMap<K, V> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.values().for_each(|value| value.each_clock(f));
+    }
+}
+
+impl<K: Hash + Ord + Clone, V: Delta> Delta for GMap<K, V> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let delta: BTreeMap<K, V> = self
+            .0
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.delta_since(seen)?)))
+            .collect();
+
+        (!delta.is_empty()).then_some(GMap(delta))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::max::Max;
-    use super::super::merge;
+    use super::super::{LWWRegister, delta, merge};
     use super::*;
     use proptest::proptest;
 
@@ -72,4 +91,20 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    crate::merge_laws!(merge_laws, GMap<bool, Max<bool>>, default);
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: GMap<bool, LWWRegister<bool>>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: GMap<bool, LWWRegister<bool>>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
 }
@@ -1,8 +1,9 @@
 use super::merge::Merge;
+use super::{Clocked, Delta, HybridLogicalClock, VersionVector};
 use std::collections::BTreeSet;
 
 #[derive(Debug, Clone, PartialEq)]
-#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
 pub struct GSet<T: Eq + Ord>(BTreeSet<T>);
 
 impl<T: Eq + Ord> GSet<T> {
@@ -19,9 +20,28 @@ impl<T: Eq + Ord> Merge for GSet<T> {
     }
 }
 
+impl<T: Eq + Ord + Clocked> Clocked for GSet<T> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.iter().for_each(|item| item.each_clock(f));
+    }
+}
+
+impl<T: Eq + Ord + Clocked + Clone> Delta for GSet<T> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let delta: BTreeSet<T> = self
+            .0
+            .iter()
+            .filter(|item| !seen.covers(*item))
+            .cloned()
+            .collect();
+
+        (!delta.is_empty()).then_some(GSet(delta))
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::super::merge;
+    use super::super::{delta, merge};
     use super::*;
     use proptest::proptest;
 
@@ -45,4 +65,20 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    crate::merge_laws!(merge_laws, GSet<bool>);
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: GSet<HybridLogicalClock>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: GSet<HybridLogicalClock>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
 }
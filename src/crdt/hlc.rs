@@ -1,22 +1,172 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use std::cmp::{Ord, Ordering};
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
-#[derive(Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+/// Where hybrid logical clocks get physical time from.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations. Clones
+/// share the same time, so several replicas can run on one virtual clock.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock(Arc::new(Mutex::new(start)))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: TimeDelta) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
 pub struct HybridLogicalClock {
-    #[cfg_attr(test, proptest(strategy = "timestamp_strategy()"))]
+    #[cfg_attr(
+        any(test, feature = "testing"),
+        proptest(strategy = "timestamp_strategy()")
+    )]
+    #[serde(with = "timestamp")]
     timestamp: DateTime<Utc>,
 
-    #[cfg_attr(test, proptest(strategy = "counter_strategy()"))]
+    #[cfg_attr(
+        any(test, feature = "testing"),
+        proptest(strategy = "counter_strategy()")
+    )]
     counter: u16,
 
-    #[cfg_attr(test, proptest(strategy = "uuid_strategy()"))]
+    #[cfg_attr(any(test, feature = "testing"), proptest(strategy = "uuid_strategy()"))]
+    #[serde(with = "node_id")]
     node_id: Uuid,
 }
 
-#[cfg(test)]
+/// Timestamps are RFC 3339 strings in human-readable formats like JSON, and
+/// nanoseconds since the Unix epoch in binary ones like CBOR, where they take
+/// a fraction of the space. Either is accepted when reading.
+mod timestamp {
+    use chrono::{DateTime, Utc};
+    use serde::de::{self, Visitor};
+    use serde::ser::{self, Serialize};
+    use std::fmt;
+
+    pub fn serialize<S: ser::Serializer>(
+        timestamp: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return timestamp.serialize(serializer);
+        }
+
+        match timestamp.timestamp_nanos_opt() {
+            Some(nanos) => serializer.serialize_i64(nanos),
+            None => Err(ser::Error::custom(format!(
+                "{timestamp} can't be written as nanoseconds"
+            ))),
+        }
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+
+    struct TimestampVisitor;
+
+    impl Visitor<'_> for TimestampVisitor {
+        type Value = DateTime<Utc>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an RFC 3339 timestamp or nanoseconds since the epoch")
+        }
+
+        fn visit_str<E: de::Error>(self, timestamp: &str) -> Result<Self::Value, E> {
+            timestamp.parse().map_err(E::custom)
+        }
+
+        fn visit_i64<E: de::Error>(self, nanos: i64) -> Result<Self::Value, E> {
+            Ok(DateTime::from_timestamp_nanos(nanos))
+        }
+
+        fn visit_u64<E: de::Error>(self, nanos: u64) -> Result<Self::Value, E> {
+            i64::try_from(nanos)
+                .map(DateTime::from_timestamp_nanos)
+                .map_err(E::custom)
+        }
+    }
+}
+
+/// Inside a document, node IDs are written as their number in the document's
+/// node table (see `NodeTable::scope`). Anywhere else, or for nodes that
+/// aren't in the table, they're a UUID in whichever form the format writes
+/// it.
+mod node_id {
+    use super::super::NodeTable;
+    use serde::de::{self, Visitor};
+    use serde::ser::{self, Serialize};
+    use std::fmt;
+    use uuid::Uuid;
+
+    pub fn serialize<S: ser::Serializer>(node_id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+        match NodeTable::current_index(node_id) {
+            Some(index) => serializer.serialize_u64(index),
+            None => node_id.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+        deserializer.deserialize_any(NodeIdVisitor)
+    }
+
+    struct NodeIdVisitor;
+
+    impl Visitor<'_> for NodeIdVisitor {
+        type Value = Uuid;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a UUID or a number in the node table")
+        }
+
+        fn visit_u64<E: de::Error>(self, index: u64) -> Result<Self::Value, E> {
+            NodeTable::current_node(index)
+        }
+
+        fn visit_str<E: de::Error>(self, node_id: &str) -> Result<Self::Value, E> {
+            Uuid::parse_str(node_id).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, node_id: &[u8]) -> Result<Self::Value, E> {
+            Uuid::from_slice(node_id).map_err(E::custom)
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
 fn timestamp_strategy() -> impl proptest::strategy::Strategy<Value = DateTime<Utc>> {
     use chrono::TimeZone;
     use proptest::prelude::*;
@@ -24,12 +174,12 @@ fn timestamp_strategy() -> impl proptest::strategy::Strategy<Value = DateTime<Ut
     (0..=2i64).prop_map(|unix| Utc.timestamp_opt(unix, 0).unwrap())
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 fn counter_strategy() -> impl proptest::strategy::Strategy<Value = u16> {
     0..=2u16
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 fn uuid_strategy() -> impl proptest::strategy::Strategy<Value = Uuid> {
     use proptest::prelude::*;
 
@@ -37,23 +187,74 @@ fn uuid_strategy() -> impl proptest::strategy::Strategy<Value = Uuid> {
 }
 
 impl HybridLogicalClock {
-    #[tracing::instrument(name = "HLC::new")]
-    pub fn new(node_id: Uuid) -> Self {
+    #[tracing::instrument(name = "HLC::new", skip(clock))]
+    pub fn new(node_id: Uuid, clock: &dyn Clock) -> Self {
         HybridLogicalClock {
-            timestamp: Utc::now(),
+            timestamp: clock.now(),
             counter: 0,
             node_id,
         }
     }
 
-    #[tracing::instrument(name = "HLC::tick", skip(self))]
-    pub fn tick(&mut self) {
-        let now = Utc::now();
+    #[tracing::instrument(name = "HLC::tick", skip(self, clock))]
+    pub fn tick(&mut self, clock: &dyn Clock) {
+        self.tick_at(clock.now());
+    }
+
+    fn tick_at(&mut self, now: DateTime<Utc>) {
         if now > self.timestamp {
             self.timestamp = now;
             self.counter = 0;
         } else {
-            self.counter += 1;
+            self.set_after(self.timestamp, self.counter);
+        }
+    }
+
+    /// Move to the clock right after `counter` at `timestamp`. When the
+    /// counter is saturated we borrow from the timestamp instead of wrapping
+    /// around, so a burst of events in the same instant stays ordered. At the
+    /// latest time we can represent there's nothing left to borrow, so the
+    /// clock stays where it is.
+    fn set_after(&mut self, timestamp: DateTime<Utc>, counter: u16) {
+        match counter.checked_add(1) {
+            Some(counter) => {
+                self.timestamp = timestamp;
+                self.counter = counter;
+            }
+            None => match timestamp.checked_add_signed(TimeDelta::nanoseconds(1)) {
+                Some(timestamp) => {
+                    self.timestamp = timestamp;
+                    self.counter = 0;
+                }
+                None => {
+                    self.timestamp = timestamp;
+                    self.counter = u16::MAX;
+                }
+            },
+        }
+    }
+
+    /// Move this clock past a clock we received from another replica, so that
+    /// every clock we issue afterwards sorts after it. This is the receive rule
+    /// from the HLC paper: take the latest of our time, their time, and the
+    /// wall clock, and bump the counter of whichever side(s) it came from.
+    #[tracing::instrument(name = "HLC::observe", skip(self, remote, clock))]
+    pub fn observe(&mut self, remote: &HybridLogicalClock, clock: &dyn Clock) {
+        self.observe_at(remote, clock.now());
+    }
+
+    fn observe_at(&mut self, remote: &HybridLogicalClock, now: DateTime<Utc>) {
+        let timestamp = self.timestamp.max(remote.timestamp).max(now);
+
+        if timestamp == self.timestamp && timestamp == remote.timestamp {
+            self.set_after(timestamp, self.counter.max(remote.counter));
+        } else if timestamp == self.timestamp {
+            self.set_after(timestamp, self.counter);
+        } else if timestamp == remote.timestamp {
+            self.set_after(timestamp, remote.counter);
+        } else {
+            self.timestamp = timestamp;
+            self.counter = 0;
         }
     }
 
@@ -64,6 +265,14 @@ impl HybridLogicalClock {
             node_id: id,
         }
     }
+
+    pub fn node_id(&self) -> Uuid {
+        self.node_id
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
 }
 
 impl Ord for HybridLogicalClock {
@@ -183,4 +392,89 @@ mod test {
             assert!(greater >= lesser, "{greater:?} < {lesser:?}");
         }
     }
+
+    proptest! {
+        #[test]
+        fn observe_passes_both_clocks(
+            local: HybridLogicalClock,
+            remote: HybridLogicalClock,
+            now in timestamp_strategy(),
+        ) {
+            let mut observed = local;
+            observed.observe_at(&remote, now);
+
+            assert!(observed > local, "{observed:?} <= {local:?}");
+            assert!(observed > remote, "{observed:?} <= {remote:?}");
+            assert_eq!(observed.node_id, local.node_id);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn ticks_after_observe_pass_remote(
+            local: HybridLogicalClock,
+            remotes: Vec<HybridLogicalClock>,
+            nows in proptest::collection::vec(timestamp_strategy(), 1..4),
+        ) {
+            let mut clock = local;
+            for remote in &remotes {
+                clock.observe_at(remote, nows[0]);
+            }
+
+            for now in nows {
+                clock.tick_at(now);
+
+                for remote in &remotes {
+                    assert!(clock > *remote, "{clock:?} <= {remote:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ticks_in_frozen_time_stay_monotonic() {
+        let frozen = ManualClock::new(DateTime::<Utc>::UNIX_EPOCH);
+        let mut clock = HybridLogicalClock::new(Uuid::nil(), &frozen);
+
+        for _ in 0..3 * (u16::MAX as usize) {
+            let before = clock;
+            clock.tick(&frozen);
+
+            assert!(clock > before, "{clock:?} <= {before:?}");
+        }
+
+        assert!(clock.timestamp > frozen.now());
+    }
+
+    #[test]
+    fn ticks_at_the_end_of_time_saturate() {
+        let mut clock = HybridLogicalClock {
+            timestamp: DateTime::<Utc>::MAX_UTC,
+            counter: u16::MAX,
+            node_id: Uuid::nil(),
+        };
+
+        clock.tick_at(DateTime::<Utc>::MAX_UTC);
+
+        assert_eq!(clock.timestamp, DateTime::<Utc>::MAX_UTC);
+        assert_eq!(clock.counter, u16::MAX);
+    }
+
+    proptest! {
+        #[test]
+        fn observe_saturated_counters(
+            local: HybridLogicalClock,
+            remote: HybridLogicalClock,
+            now in timestamp_strategy(),
+        ) {
+            let local = HybridLogicalClock { counter: u16::MAX, ..local };
+            let remote = HybridLogicalClock { counter: u16::MAX, ..remote };
+
+            let mut observed = local;
+            observed.observe_at(&remote, now);
+
+            assert!(observed > local, "{observed:?} <= {local:?}");
+            assert!(observed > remote, "{observed:?} <= {remote:?}");
+        }
+    }
 }
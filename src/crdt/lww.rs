@@ -1,8 +1,8 @@
-use super::{HybridLogicalClock, Merge};
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
 use std::fmt::Debug;
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
 pub struct LWWRegister<T: Debug> {
     value: T,
     clock: HybridLogicalClock,
@@ -28,7 +28,6 @@ impl<T: Debug> LWWRegister<T> {
         &self.value
     }
 
-    #[cfg(test)]
     #[tracing::instrument(name = "LWW::clock", skip(self))]
     pub fn clock(&self) -> &HybridLogicalClock {
         &self.clock
@@ -43,9 +42,21 @@ impl<T: Debug> Merge for LWWRegister<T> {
     }
 }
 
+impl<T: Debug> Clocked for LWWRegister<T> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        f(&self.clock)
+    }
+}
+
+impl<T: Debug + Clone> Delta for LWWRegister<T> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        (!seen.contains(&self.clock)).then(|| self.clone())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::super::merge;
+    use super::super::{delta, merge};
     use super::*;
     use proptest::prelude::*;
 
@@ -75,4 +86,18 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: LWWRegister<bool>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: LWWRegister<bool>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
 }
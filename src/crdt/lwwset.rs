@@ -1,8 +1,8 @@
-use super::{HybridLogicalClock, Merge};
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
 use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
 pub struct LWWSet<T: Ord> {
     adds: BTreeMap<T, HybridLogicalClock>,
     removes: BTreeMap<T, HybridLogicalClock>,
@@ -31,6 +31,24 @@ impl<T: Ord> LWWSet<T> {
     pub fn contains(&self, item: &T) -> bool {
         self.adds.get(item) > self.removes.get(item)
     }
+
+    /// Forget removed items whose removes every replica has seen. Returns the
+    /// number of tombstones dropped.
+    #[tracing::instrument(name = "LWWSet::gc", skip(self, stable))]
+    pub fn gc(&mut self, stable: &VersionVector) -> usize {
+        let before = self.removes.len();
+
+        self.removes.retain(|item, clock| {
+            let collect = stable.contains(clock) && self.adds.get(item) <= Some(clock);
+            if collect {
+                self.adds.remove(item);
+            }
+
+            !collect
+        });
+
+        before - self.removes.len()
+    }
 }
 
 impl<T: Ord> Merge for LWWSet<T> {
@@ -58,9 +76,33 @@ where
     }
 }
 
+impl<T: Ord> Clocked for LWWSet<T> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.adds.values().chain(self.removes.values()).for_each(f);
+    }
+}
+
+impl<T: Ord + Clone> Delta for LWWSet<T> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let unseen = |map: &BTreeMap<T, HybridLogicalClock>| -> BTreeMap<T, HybridLogicalClock> {
+            map.iter()
+                .filter(|(_, clock)| !seen.contains(clock))
+                .map(|(item, clock)| (item.clone(), *clock))
+                .collect()
+        };
+
+        let delta = LWWSet {
+            adds: unseen(&self.adds),
+            removes: unseen(&self.removes),
+        };
+
+        (delta != Self::default()).then_some(delta)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::super::merge;
+    use super::super::{delta, merge};
     use super::*;
     use proptest::prelude::*;
 
@@ -84,4 +126,30 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    crate::merge_laws!(merge_laws, LWWSet<bool>, default);
+
+    proptest! {
+        #[test]
+        fn gc_keeps_members(v: LWWSet<bool>, stable: VersionVector) {
+            let mut collected = v.clone();
+            collected.gc(&stable);
+
+            assert_eq!(collected.iter().collect::<Vec<_>>(), v.iter().collect::<Vec<_>>());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: LWWSet<bool>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: LWWSet<bool>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
 }
@@ -47,4 +47,6 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    crate::merge_laws!(merge_laws, Max<bool>);
 }
@@ -1,7 +1,14 @@
+pub use rust_crdt_talk_derive::Merge;
+
+// For the spans `#[derive(Merge)]` makes, so crates using it don't need
+// their own dependency on `tracing`.
+#[doc(hidden)]
+pub use tracing;
+
 pub trait Merge {
     fn merge_mut(&mut self, other: Self);
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     fn merge(self, other: Self) -> Self
     where
         Self: Clone,
@@ -13,9 +20,23 @@ pub trait Merge {
     }
 }
 
+/// `None` is the bottom of the lattice: merging with it keeps the other side.
+impl<T: Merge> Merge for Option<T> {
+    fn merge_mut(&mut self, other: Self) {
+        match self {
+            Some(ours) => {
+                if let Some(theirs) = other {
+                    ours.merge_mut(theirs);
+                }
+            }
+            None => *self = other,
+        }
+    }
+}
+
 /// Test that a Merge implementation is idempotent (in other words, merging
 /// multiple times should not change the state.)
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 pub fn test_idempotent<T>(v: T)
 where
     T: Merge + Clone + PartialEq + std::fmt::Debug,
@@ -25,7 +46,7 @@ where
 
 /// Test that the implementation is commutative (in other words, the order of
 /// merges should not effect the final result.)
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 pub fn test_commutative<T>(a: T, b: T)
 where
     T: Merge + Clone + PartialEq + std::fmt::Debug,
@@ -36,9 +57,32 @@ where
     assert_eq!(ab, ba);
 }
 
+/// Test that merging only ever moves forward (in other words, merging `a` into
+/// something that already includes `a` changes nothing.)
+#[cfg(any(test, feature = "testing"))]
+pub fn test_inflationary<T>(a: T, b: T)
+where
+    T: Merge + Clone + PartialEq + std::fmt::Debug,
+{
+    let ab = a.clone().merge(b);
+
+    assert_eq!(ab.clone().merge(a), ab);
+}
+
+/// Test that the default value is empty (in other words, merging with it on
+/// either side changes nothing.)
+#[cfg(any(test, feature = "testing"))]
+pub fn test_default_is_identity<T>(v: T)
+where
+    T: Merge + Default + Clone + PartialEq + std::fmt::Debug,
+{
+    assert_eq!(v.clone().merge(T::default()), v);
+    assert_eq!(T::default().merge(v.clone()), v);
+}
+
 /// Test that a Merge implementation is associative (in other words, the order
 /// in which replicas are merged should not effect the final result.)
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 pub fn test_associative<T>(a: T, b: T, c: T)
 where
     T: Merge + Clone + PartialEq + std::fmt::Debug,
@@ -48,3 +92,102 @@ where
 
     assert_eq!(ab_c, a_bc);
 }
+
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+pub use proptest;
+
+/// Generate a module of proptests checking that a `Merge` type is a lattice:
+/// idempotent, commutative, associative and inflationary. Add `default` to
+/// also check that its `Default` is the identity.
+///
+/// ```ignore
+/// merge_laws!(task_laws, Task);
+/// merge_laws!(tags_laws, ORMap<String, Tag>, default);
+/// ```
+///
+/// The type needs a proptest `Arbitrary` impl; the ones in this crate are
+/// available with the `testing` feature. `tests/merge_laws.rs` uses it the
+/// way another crate would. Types that count on clocks being
+/// unique (like `LWWRegister`) will fail `commutative` when proptest picks two
+/// equal clocks, so test those by hand with `prop_assume!`.
+#[cfg(any(test, feature = "testing"))]
+#[macro_export]
+macro_rules! merge_laws {
+    ($name:ident, $type:ty, default) => {
+        $crate::merge_laws!(@laws $name, $type, {
+            $crate::crdt::merge::proptest::proptest! {
+                #[test]
+                fn default_is_identity(v: $type) {
+                    $crate::crdt::merge::test_default_is_identity(v);
+                }
+            }
+        });
+    };
+
+    ($name:ident, $type:ty) => {
+        $crate::merge_laws!(@laws $name, $type, {});
+    };
+
+    (@laws $name:ident, $type:ty, { $($extra:tt)* }) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $crate::crdt::merge::proptest::proptest! {
+                #[test]
+                fn idempotent(v: $type) {
+                    $crate::crdt::merge::test_idempotent(v);
+                }
+
+                #[test]
+                fn commutative(a: $type, b: $type) {
+                    $crate::crdt::merge::test_commutative(a, b);
+                }
+
+                #[test]
+                fn associative(a: $type, b: $type, c: $type) {
+                    $crate::crdt::merge::test_associative(a, b, c);
+                }
+
+                #[test]
+                fn inflationary(a: $type, b: $type) {
+                    $crate::crdt::merge::test_inflationary(a, b);
+                }
+            }
+
+            $($extra)*
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::max::Max;
+    use super::*;
+    use proptest::prelude::*;
+    use proptest_derive::Arbitrary;
+
+    #[derive(Debug, Clone, PartialEq, Merge, Arbitrary)]
+    struct Named {
+        first: Max<bool>,
+        second: Max<u8>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Merge, Arbitrary)]
+    struct Tuple(Max<bool>, Max<u8>);
+
+    crate::merge_laws!(named_laws, Named);
+    crate::merge_laws!(tuple_laws, Tuple);
+    crate::merge_laws!(option_laws, Option<Max<u8>>, default);
+
+    proptest! {
+        #[test]
+        fn derived_merge_is_field_wise(a: Named, b: Named) {
+            let merged = a.clone().merge(b.clone());
+
+            assert_eq!(merged.first, a.first.merge(b.first));
+            assert_eq!(merged.second, a.second.merge(b.second));
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use std::fmt::Debug;
+
+#[cfg(any(test, feature = "testing"))]
+use proptest::arbitrary::{Arbitrary, ParamsFor, StrategyFor};
+
+/// A multi-value register. Setting a value replaces every value this replica
+/// has seen, but values set concurrently on other replicas survive the merge
+/// side by side until someone sets a new value over all of them.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "StoredMVRegister<T>")]
+pub struct MVRegister<T: Debug> {
+    values: Vec<(HybridLogicalClock, T)>,
+    context: VersionVector,
+}
+
+/// An `MVRegister` as it's written, before we've checked it has a value.
+#[derive(serde::Deserialize)]
+struct StoredMVRegister<T> {
+    values: Vec<(HybridLogicalClock, T)>,
+    context: VersionVector,
+}
+
+impl<T: Debug> TryFrom<StoredMVRegister<T>> for MVRegister<T> {
+    type Error = &'static str;
+
+    fn try_from(stored: StoredMVRegister<T>) -> Result<Self, Self::Error> {
+        if stored.values.is_empty() {
+            return Err("an MVRegister has to have at least one value");
+        }
+
+        Ok(MVRegister {
+            values: stored.values,
+            context: stored.context,
+        })
+    }
+}
+
+impl<T: Debug> MVRegister<T> {
+    #[tracing::instrument(name = "MV::new", skip(clock))]
+    pub fn new(value: T, clock: HybridLogicalClock) -> Self {
+        let mut context = VersionVector::default();
+        context.observe(clock);
+
+        MVRegister {
+            values: vec![(clock, value)],
+            context,
+        }
+    }
+
+    #[tracing::instrument(name = "MV::set", skip(self, clock))]
+    pub fn set(&mut self, value: T, clock: HybridLogicalClock) {
+        self.values.clear();
+        self.values.push((clock, value));
+        self.context.observe(clock);
+    }
+
+    /// The most recently set value. If there are conflicts, the others are
+    /// available from `values`.
+    #[tracing::instrument(name = "MV::value", skip(self))]
+    pub fn value(&self) -> &T {
+        let (_, value) = self
+            .values
+            .last()
+            .expect("an MVRegister always has at least one value");
+
+        value
+    }
+
+    /// All concurrently set values, oldest first.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.values.iter().map(|(_, value)| value)
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.values.len() > 1
+    }
+
+    /// The values on their own, with only their own clocks as context.
+    /// Merging this into a register that has seen them changes nothing, so
+    /// it can stand in for a register that hasn't changed.
+    pub fn without_context(&self) -> Self
+    where
+        T: Clone,
+    {
+        let mut context = VersionVector::default();
+        for (clock, _) in &self.values {
+            context.observe(*clock);
+        }
+
+        MVRegister {
+            values: self.values.clone(),
+            context,
+        }
+    }
+}
+
+impl<T: Debug + Ord> Merge for MVRegister<T> {
+    #[tracing::instrument(name = "MV::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        let our_clocks: BTreeSet<HybridLogicalClock> =
+            self.values.iter().map(|(clock, _)| *clock).collect();
+        let their_clocks: BTreeSet<HybridLogicalClock> =
+            other.values.iter().map(|(clock, _)| *clock).collect();
+
+        // A value survives if the other side has it too, or if the other side
+        // has never seen it (and so can't have overwritten it.)
+        let mut values = BTreeMap::new();
+        for (clock, value) in std::mem::take(&mut self.values) {
+            if their_clocks.contains(&clock) || !other.context.contains(&clock) {
+                values.insert(clock, value);
+            }
+        }
+
+        for (clock, value) in other.values {
+            if our_clocks.contains(&clock) || !self.context.contains(&clock) {
+                match values.entry(clock) {
+                    Entry::Occupied(mut existing) => {
+                        if value > *existing.get() {
+                            existing.insert(value);
+                        }
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(value);
+                    }
+                }
+            }
+        }
+
+        self.values = values.into_iter().collect();
+        self.context.merge_mut(other.context);
+    }
+}
+
+impl<T: Debug> Clocked for MVRegister<T> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.values.iter().for_each(|(clock, _)| f(clock));
+        self.context.each_clock(f);
+    }
+}
+
+impl<T: Debug + Ord + Clone> Delta for MVRegister<T> {
+    /// The whole register if any value is new, since the context has to travel
+    /// with the values for the merge to drop the ones they overwrote.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        (!seen.covers(self)).then(|| self.clone())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl<T: Debug + Ord + Arbitrary> Arbitrary for MVRegister<T> {
+    type Parameters = ParamsFor<T>;
+
+    type Strategy = proptest::strategy::Map<
+        StrategyFor<Vec<Vec<(T, HybridLogicalClock)>>>,
+        fn(Vec<Vec<(T, HybridLogicalClock)>>) -> Self,
+    >;
+
+    fn arbitrary_with(params: Self::Parameters) -> Self::Strategy {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        // Each inner list is a run of sets on one replica (so its clocks only
+        // go up.) Merging the runs together gives us concurrent values.
+        proptest::strategy::Strategy::prop_map(
+            vec(
+                vec((any_with::<T>(params), any::<HybridLogicalClock>()), 1..3),
+                1..3,
+            ),
+            |replicas| {
+                replicas
+                    .into_iter()
+                    .map(|mut sets| {
+                        sets.sort_by_key(|(_, clock)| *clock);
+
+                        let mut sets = sets.into_iter();
+                        let (value, clock) = sets.next().unwrap();
+                        let mut register = Self::new(value, clock);
+
+                        for (value, clock) in sets {
+                            register.set(value, clock);
+                        }
+
+                        register
+                    })
+                    .reduce(|mut a, b| {
+                        a.merge_mut(b);
+                        a
+                    })
+                    .unwrap()
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::delta;
+    use super::super::hlc::WallClock;
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(merge_laws, MVRegister<bool>);
+
+    proptest! {
+        #[test]
+        fn concurrent_sets_are_kept(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first.node_id() != second.node_id());
+
+            let a = MVRegister::new(false, first);
+            let b = MVRegister::new(true, second);
+
+            let merged = a.merge(b);
+            assert!(merged.is_conflicted());
+            assert_eq!(merged.values().count(), 2);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn set_after_merge_resolves(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first.node_id() != second.node_id());
+
+            let mut third = first.max(second);
+            third.tick(&WallClock);
+
+            let mut merged = MVRegister::new(false, first).merge(MVRegister::new(true, second));
+            merged.set(true, third);
+
+            let stale = MVRegister::new(false, first);
+            let merged = merged.merge(stale);
+            assert!(!merged.is_conflicted());
+            assert_eq!(merged.value(), &true);
+        }
+    }
+
+    #[test]
+    fn registers_without_values_dont_load() {
+        let empty = r#"{"values": [], "context": []}"#;
+
+        assert!(serde_json::from_str::<MVRegister<bool>>(empty).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: MVRegister<bool>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: MVRegister<bool>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use super::Merge;
+use serde::de;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Numbers for the replicas a document's clocks came from, so its clocks can
+/// refer to a replica by number instead of repeating the whole UUID. A
+/// replica keeps its number once it has one, so a new replica showing up
+/// doesn't change how clocks from all the others are written.
+#[derive(Debug, Clone, Default)]
+pub struct NodeTable {
+    nodes: Vec<Uuid>,
+    indexes: HashMap<Uuid, u64>,
+}
+
+thread_local! {
+    /// The table clocks are read and written against, while `NodeTable::scope`
+    /// is running.
+    static CURRENT: RefCell<Option<NodeTable>> = const { RefCell::new(None) };
+}
+
+impl NodeTable {
+    pub fn new(nodes: Vec<Uuid>) -> Self {
+        let mut table = NodeTable::default();
+        for node in nodes {
+            table.insert(node);
+        }
+
+        table
+    }
+
+    pub fn nodes(&self) -> &[Uuid] {
+        &self.nodes
+    }
+
+    /// Give `node` the next number, unless it already has one.
+    pub fn insert(&mut self, node: Uuid) {
+        if !self.indexes.contains_key(&node) {
+            self.indexes.insert(node, self.nodes.len() as u64);
+            self.nodes.push(node);
+        }
+    }
+
+    /// Run `f` with this as the current table: clocks serialized on this
+    /// thread in the meantime write the number of their node instead of its
+    /// UUID (unless it isn't in the table), and clocks deserialized look
+    /// numbers up in it. Afterwards the table in use before (if any) is
+    /// current again, and this one is handed back.
+    pub fn scope<T>(self, f: impl FnOnce() -> T) -> (Self, T) {
+        struct Restore(Option<NodeTable>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.set(self.0.take());
+            }
+        }
+
+        let restore = Restore(CURRENT.replace(Some(self)));
+        let result = f();
+        let table = CURRENT
+            .take()
+            .expect("the node table is current until the scope ends");
+        drop(restore);
+
+        (table, result)
+    }
+
+    /// The number of `node` in the current table, if there is one and `node`
+    /// is in it.
+    pub(super) fn current_index(node: &Uuid) -> Option<u64> {
+        CURRENT.with_borrow(|table| table.as_ref()?.indexes.get(node).copied())
+    }
+
+    /// The node numbered `index` in the current table.
+    pub(super) fn current_node<E: de::Error>(index: u64) -> Result<Uuid, E> {
+        CURRENT.with_borrow(|table| {
+            let table = table
+                .as_ref()
+                .ok_or_else(|| E::custom(format!("node {index} used outside a node table")))?;
+
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| table.nodes.get(index).copied())
+                .ok_or_else(|| {
+                    E::custom(format!(
+                        "node {index} isn't in the node table ({} nodes)",
+                        table.nodes.len()
+                    ))
+                })
+        })
+    }
+}
+
+/// Merging keeps our numbers and gives replicas only the other table knows
+/// about the next ones. The table isn't replicated data, so it doesn't matter
+/// that this depends on which side merges into which.
+impl Merge for NodeTable {
+    fn merge_mut(&mut self, other: Self) {
+        for node in other.nodes {
+            self.insert(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::HybridLogicalClock;
+    use super::super::hlc::WallClock;
+    use super::*;
+    use proptest::prelude::*;
+
+    fn table(clocks: &[HybridLogicalClock]) -> NodeTable {
+        let mut table = NodeTable::default();
+        for clock in clocks {
+            table.insert(clock.node_id());
+        }
+
+        table
+    }
+
+    proptest! {
+        #[test]
+        fn clocks_round_trip_in_a_scope(clocks: Vec<HybridLogicalClock>) {
+            let (table, json) = table(&clocks).scope(|| serde_json::to_value(&clocks).unwrap());
+            for clock in json.as_array().unwrap() {
+                assert!(clock["node_id"].is_u64(), "{clock}");
+            }
+
+            let (table, cbor) = table.scope(|| {
+                let mut cbor = Vec::new();
+                ciborium::into_writer(&clocks, &mut cbor).unwrap();
+                cbor
+            });
+
+            let (table, decoded) = table.scope(|| {
+                serde_json::from_value::<Vec<HybridLogicalClock>>(json).unwrap()
+            });
+            assert_eq!(decoded, clocks);
+
+            let (_, decoded) = table.scope(|| {
+                ciborium::from_reader::<Vec<HybridLogicalClock>, _>(&cbor[..]).unwrap()
+            });
+            assert_eq!(decoded, clocks);
+        }
+    }
+
+    #[test]
+    fn nodes_outside_the_table_keep_their_uuids() {
+        let clock = HybridLogicalClock::new(Uuid::from_u128(1), &WallClock);
+        let table = NodeTable::new(vec![Uuid::from_u128(2)]);
+
+        let (_, json) = table.scope(|| serde_json::to_value(clock).unwrap());
+        assert_eq!(json["node_id"], Uuid::from_u128(1).to_string());
+    }
+
+    #[test]
+    fn clocks_outside_a_scope_keep_their_uuids() {
+        let clock = HybridLogicalClock::new(Uuid::from_u128(1), &WallClock);
+        NodeTable::new(vec![Uuid::from_u128(1)]).scope(|| ());
+
+        let json = serde_json::to_value(clock).unwrap();
+        assert_eq!(json["node_id"], Uuid::from_u128(1).to_string());
+    }
+
+    #[test]
+    fn only_clocks_are_numbered() {
+        let value = serde_json::json!({ "timestamp": 1, "counter": 2, "node_id": Uuid::nil() });
+        let table = NodeTable::new(vec![Uuid::nil()]);
+
+        let (_, json) = table.scope(|| serde_json::to_value(&value).unwrap());
+        assert_eq!(json, value);
+    }
+
+    #[test]
+    fn new_nodes_go_after_the_ones_already_numbered() {
+        let mut table = NodeTable::new(vec![Uuid::from_u128(2)]);
+        table.merge_mut(NodeTable::new(vec![Uuid::from_u128(1), Uuid::from_u128(2)]));
+
+        assert_eq!(table.nodes(), [Uuid::from_u128(2), Uuid::from_u128(1)]);
+    }
+}
@@ -0,0 +1,362 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use std::fmt::Debug;
+
+#[cfg(any(test, feature = "testing"))]
+use proptest::arbitrary::{Arbitrary, ParamsFor, StrategyFor};
+
+/// An add-wins observed-remove map. Every insert (or edit through `get_mut`)
+/// tags the key with a clock, and removing a key only tombstones the tags this
+/// replica has seen. A concurrent insert or edit on another replica carries a
+/// tag we haven't removed, so the key survives the merge.
+///
+/// Tombstones remember the clock of the remove so that `gc` can drop them
+/// once every replica has seen it.
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ORMap<K: Ord + Debug + Clone, V: Merge> {
+    entries: BTreeMap<K, Tagged<V>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+struct Tagged<V> {
+    value: V,
+    adds: BTreeSet<HybridLogicalClock>,
+    removes: BTreeSet<HybridLogicalClock>,
+    removed_at: BTreeSet<HybridLogicalClock>,
+}
+
+impl<V> Tagged<V> {
+    fn is_live(&self) -> bool {
+        self.adds.iter().any(|tag| !self.removes.contains(tag))
+    }
+
+    fn remove(&mut self, clock: HybridLogicalClock) {
+        self.removes.extend(self.adds.iter().copied());
+        self.removed_at.insert(clock);
+    }
+}
+
+impl<V: Delta + Clone> Tagged<V> {
+    /// The new tags and value changes in this entry. Removes tombstone add
+    /// tags the other replica may already have, so they travel whole whenever
+    /// there is a remove it hasn't seen.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let unseen = |clocks: &BTreeSet<HybridLogicalClock>| -> BTreeSet<HybridLogicalClock> {
+            clocks
+                .iter()
+                .filter(|clock| !seen.contains(clock))
+                .copied()
+                .collect()
+        };
+
+        let adds = unseen(&self.adds);
+        let removed_at = unseen(&self.removed_at);
+        let value = self.value.delta_since(seen);
+
+        if adds.is_empty() && removed_at.is_empty() && value.is_none() {
+            return None;
+        }
+
+        Some(Tagged {
+            value: value.unwrap_or_else(|| self.value.clone()),
+            adds,
+            removes: if removed_at.is_empty() {
+                BTreeSet::new()
+            } else {
+                self.removes.clone()
+            },
+            removed_at,
+        })
+    }
+}
+
+impl<V: Merge> Merge for Tagged<V> {
+    fn merge_mut(&mut self, mut other: Self) {
+        self.value.merge_mut(other.value);
+        self.adds.append(&mut other.adds);
+        self.removes.append(&mut other.removes);
+        self.removed_at.append(&mut other.removed_at);
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Merge> ORMap<K, V> {
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter(|(_, tagged)| tagged.is_live())
+            .map(|(k, tagged)| (k, &tagged.value))
+    }
+
+    /// Every entry, including removed ones, along with the clocks that track
+    /// whether it is in the map.
+    pub fn entries(
+        &self,
+    ) -> impl Iterator<Item = (&K, &V, impl Iterator<Item = &HybridLogicalClock>)> {
+        self.entries.iter().map(|(k, tagged)| {
+            let clocks = tagged
+                .adds
+                .iter()
+                .chain(&tagged.removes)
+                .chain(&tagged.removed_at);
+
+            (k, &tagged.value, clocks)
+        })
+    }
+
+    #[tracing::instrument(name = "ORMap::contains_key", skip(self))]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.get(key).is_some_and(Tagged::is_live)
+    }
+
+    #[tracing::instrument(name = "ORMap::get", skip(self))]
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries
+            .get(key)
+            .filter(|tagged| tagged.is_live())
+            .map(|tagged| &tagged.value)
+    }
+
+    #[tracing::instrument(name = "ORMap::insert", skip(self, value, clock))]
+    pub fn insert(&mut self, key: K, value: V, clock: HybridLogicalClock) {
+        let tagged = Tagged {
+            value,
+            adds: BTreeSet::from([clock]),
+            removes: BTreeSet::new(),
+            removed_at: BTreeSet::new(),
+        };
+
+        match self.entries.entry(key) {
+            Entry::Occupied(mut existing) => {
+                existing.get_mut().merge_mut(tagged);
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(tagged);
+            }
+        }
+    }
+
+    /// Get a live value for editing. The edit counts as an add at `clock`, so
+    /// it wins over a concurrent remove on another replica.
+    #[tracing::instrument(name = "ORMap::get_mut", skip(self, clock))]
+    pub fn get_mut(&mut self, key: &K, clock: HybridLogicalClock) -> Option<&mut V> {
+        let tagged = self
+            .entries
+            .get_mut(key)
+            .filter(|tagged| tagged.is_live())?;
+        tagged.adds.insert(clock);
+
+        Some(&mut tagged.value)
+    }
+
+    #[tracing::instrument(name = "ORMap::remove", skip(self, clock))]
+    pub fn remove(&mut self, key: &K, clock: HybridLogicalClock) {
+        if let Some(tagged) = self.entries.get_mut(key) {
+            tagged.remove(clock);
+        }
+    }
+
+    /// Drop `key`'s entry entirely, as if it had never been added. Unlike
+    /// `remove`, this leaves nothing behind to win against adds from other
+    /// replicas, so merging one in brings the entry back.
+    #[tracing::instrument(name = "ORMap::forget", skip(self))]
+    pub fn forget(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    #[tracing::instrument(name = "ORMap::retain", skip(self, clock, decider))]
+    pub fn retain(&mut self, clock: HybridLogicalClock, decider: impl Fn(&K, &V) -> bool) {
+        for (k, tagged) in self.entries.iter_mut() {
+            if tagged.is_live() && !decider(k, &tagged.value) {
+                tagged.remove(clock);
+            }
+        }
+    }
+
+    /// Drop removed entries whose removes every replica has seen. Returns the
+    /// number of entries dropped.
+    #[tracing::instrument(name = "ORMap::gc", skip(self, stable))]
+    pub fn gc(&mut self, stable: &VersionVector) -> usize {
+        let before = self.entries.len();
+
+        self.entries.retain(|_, tagged| {
+            tagged.is_live() || !tagged.removed_at.iter().all(|clock| stable.contains(clock))
+        });
+
+        before - self.entries.len()
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Merge> Merge for ORMap<K, V> {
+    #[tracing::instrument(name = "ORMap::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        for (key, tagged) in other.entries {
+            match self.entries.entry(key) {
+                Entry::Occupied(mut existing) => {
+                    existing.get_mut().merge_mut(tagged);
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(tagged);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Merge> Default for ORMap<K, V> {
+    #[tracing::instrument(name = "ORMap::default")]
+    fn default() -> Self {
+        ORMap {
+            entries: BTreeMap::default(),
+        }
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Merge + Clocked> Clocked for ORMap<K, V> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        for tagged in self.entries.values() {
+            tagged.value.each_clock(f);
+            tagged
+                .adds
+                .iter()
+                .chain(&tagged.removes)
+                .chain(&tagged.removed_at)
+                .for_each(&mut *f);
+        }
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Delta + Clone> Delta for ORMap<K, V> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let entries: BTreeMap<K, Tagged<V>> = self
+            .entries
+            .iter()
+            .filter_map(|(key, tagged)| Some((key.clone(), tagged.delta_since(seen)?)))
+            .collect();
+
+        (!entries.is_empty()).then_some(ORMap { entries })
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl<K: Ord + Debug + Clone + Arbitrary, V: Merge + Arbitrary> Arbitrary for ORMap<K, V> {
+    type Parameters = (ParamsFor<K>, ParamsFor<V>);
+
+    type Strategy = proptest::strategy::Map<
+        StrategyFor<Vec<(K, V, HybridLogicalClock, bool)>>,
+        fn(Vec<(K, V, HybridLogicalClock, bool)>) -> Self,
+    >;
+
+    fn arbitrary_with(params: Self::Parameters) -> Self::Strategy {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        let (k_param, v_param) = params;
+
+        proptest::strategy::Strategy::prop_map(
+            vec(
+                (
+                    any_with::<K>(k_param),
+                    any_with::<V>(v_param),
+                    any::<HybridLogicalClock>(),
+                    any::<bool>(),
+                ),
+                1..4,
+            ),
+            |items| {
+                let mut map = Self::default();
+
+                for (key, value, clock, removed) in items {
+                    map.insert(key.clone(), value, clock);
+
+                    if removed {
+                        map.remove(&key, clock);
+                    }
+                }
+
+                map
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::max::Max;
+    use super::super::{LWWRegister, delta};
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(merge_laws, ORMap<bool, Max<bool>>, default);
+
+    proptest! {
+        #[test]
+        fn concurrent_edit_wins_over_remove(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first != second);
+
+            let mut removed: ORMap<bool, Max<bool>> = ORMap::default();
+            removed.insert(true, Max::from(false), first);
+
+            let mut edited = removed.clone();
+            removed.remove(&true, first);
+            edited.get_mut(&true, second).unwrap().merge_mut(Max::from(true));
+
+            let merged = removed.merge(edited);
+            assert_eq!(merged.iter().collect::<Vec<_>>(), vec![(&true, &Max::from(true))]);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn reinsert_after_remove(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first != second);
+
+            let mut map: ORMap<bool, Max<bool>> = ORMap::default();
+            map.insert(true, Max::from(false), first);
+            map.remove(&true, first);
+            assert!(!map.contains_key(&true));
+
+            map.insert(true, Max::from(false), second);
+            assert!(map.contains_key(&true));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn gc_drops_stable_removes(added: HybridLogicalClock, removed: HybridLogicalClock) {
+            let mut map: ORMap<bool, Max<bool>> = ORMap::default();
+            map.insert(true, Max::from(false), added);
+            map.remove(&true, removed);
+
+            assert_eq!(map.gc(&VersionVector::default()), 0);
+
+            let mut stable = VersionVector::default();
+            stable.observe(removed);
+            assert_eq!(map.gc(&stable), 1);
+            assert_eq!(map, ORMap::default());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn gc_keeps_live_entries(v: ORMap<bool, Max<bool>>, stable: VersionVector) {
+            let mut collected = v.clone();
+            collected.gc(&stable);
+
+            assert_eq!(collected.iter().collect::<Vec<_>>(), v.iter().collect::<Vec<_>>());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: ORMap<bool, LWWRegister<bool>>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: ORMap<bool, LWWRegister<bool>>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::OnceLock;
+
+#[cfg(any(test, feature = "testing"))]
+use proptest::arbitrary::{Arbitrary, ParamsFor, StrategyFor};
+
+/// A replicated growable array: a sequence where every element is identified
+/// by the clock of its insert and remembers the element it was inserted
+/// after. Elements inserted after the same element are ordered newest first,
+/// and each element's whole run of followers comes before its next sibling,
+/// so text typed on one replica stays together instead of interleaving with
+/// concurrent typing on another.
+///
+/// Removed elements stay around as tombstones while other elements may
+/// still be positioned after them. `gc` drops the ones nothing follows once
+/// every replica has seen them removed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RGA<T: Ord> {
+    inserts: BTreeSet<Insert<T>>,
+    removes: BTreeSet<Remove>,
+
+    /// The last element that hasn't been removed (or `None` if there isn't
+    /// one), once something has asked for it. Putting the elements in order
+    /// is the expensive part of appending, so appends after it keep it up to
+    /// date instead of starting over.
+    #[serde(skip)]
+    last: OnceLock<Option<ElementId>>,
+}
+
+impl<T: Ord> PartialEq for RGA<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inserts == other.inserts && self.removes == other.removes
+    }
+}
+
+impl<T: Ord> Eq for RGA<T> {}
+
+/// Identifies an element. One operation can insert several elements at the
+/// same clock (like a run of typed characters), so each gets an offset too.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+pub struct ElementId {
+    #[serde(flatten)]
+    pub clock: HybridLogicalClock,
+
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u32,
+}
+
+fn is_zero(offset: &u32) -> bool {
+    *offset == 0
+}
+
+impl ElementId {
+    pub fn new(clock: HybridLogicalClock, offset: u32) -> Self {
+        ElementId { clock, offset }
+    }
+}
+
+impl From<HybridLogicalClock> for ElementId {
+    fn from(clock: HybridLogicalClock) -> Self {
+        ElementId::new(clock, 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+struct Insert<T> {
+    id: ElementId,
+    after: Option<ElementId>,
+    value: T,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+struct Remove {
+    id: ElementId,
+    at: HybridLogicalClock,
+}
+
+impl<T: Ord> RGA<T> {
+    /// The elements that haven't been removed, in order, along with their IDs.
+    #[tracing::instrument(name = "RGA::iter", skip(self))]
+    pub fn iter(&self) -> impl Iterator<Item = (&ElementId, &T)> {
+        // IDs are unique, so there should only ever be one insert per ID. If
+        // there's more, the last one wins so every replica picks the same.
+        // Inserts are sorted by ID, so any others with the same ID come right
+        // before it. Everything after this goes by hashes rather than
+        // comparing clocks, since there's a lot of them.
+        let inserts: Vec<&Insert<T>> = self
+            .inserts
+            .iter()
+            .coalesce(|previous, insert| {
+                if previous.id == insert.id {
+                    Ok(insert)
+                } else {
+                    Err((previous, insert))
+                }
+            })
+            .collect();
+
+        let mut children: HashMap<Option<&ElementId>, Vec<&Insert<T>>> = HashMap::new();
+        for insert in &inserts {
+            children
+                .entry(insert.after.as_ref())
+                .or_default()
+                .push(insert);
+        }
+
+        let removed: HashSet<&ElementId> = self.removes.iter().map(|remove| &remove.id).collect();
+
+        // Children are in ascending order, so popping them off the stack
+        // visits the newest first. Elements whose predecessor we haven't
+        // received yet are skipped until it arrives.
+        let mut stack = children.remove(&None).unwrap_or_default();
+        let mut ordered = Vec::with_capacity(inserts.len());
+        while let Some(insert) = stack.pop() {
+            if !removed.contains(&insert.id) {
+                ordered.push((&insert.id, &insert.value));
+            }
+
+            if let Some(followers) = children.remove(&Some(&insert.id)) {
+                stack.extend(followers);
+            }
+        }
+
+        ordered.into_iter()
+    }
+
+    /// The ID of the last element that hasn't been removed.
+    #[tracing::instrument(name = "RGA::last", skip(self))]
+    pub fn last(&self) -> Option<ElementId> {
+        *self
+            .last
+            .get_or_init(|| self.iter().last().map(|(id, _)| *id))
+    }
+
+    /// Insert `value` right after the element with ID `after`, or at the
+    /// start if `after` is `None`. The new element's ID has to be newer than
+    /// every ID this replica has seen, except others from the same operation.
+    #[tracing::instrument(name = "RGA::insert_after", skip(self, value, id))]
+    pub fn insert_after(&mut self, after: Option<ElementId>, value: T, id: ElementId) {
+        self.inserts.insert(Insert { id, after, value });
+
+        // Being the newest, the element comes right after `after`, so it's
+        // last if `after` was. Anywhere else, we'd have to look.
+        if self.last.get() == Some(&after) {
+            self.last = OnceLock::from(Some(id));
+        } else {
+            self.last = OnceLock::new();
+        }
+    }
+
+    #[tracing::instrument(name = "RGA::remove", skip(self, clock))]
+    pub fn remove(&mut self, id: &ElementId, clock: HybridLogicalClock) {
+        self.removes.insert(Remove { id: *id, at: clock });
+
+        if self.last.get() == Some(&Some(*id)) {
+            self.last = OnceLock::new();
+        }
+    }
+
+    /// Drop removed elements that every replica has seen removed and that no
+    /// other element is positioned after. Returns how many were dropped.
+    #[tracing::instrument(name = "RGA::gc", skip(self, stable))]
+    pub fn gc(&mut self, stable: &VersionVector) -> usize {
+        let mut followers: BTreeMap<ElementId, usize> = BTreeMap::new();
+        for insert in &self.inserts {
+            if let Some(after) = insert.after {
+                *followers.entry(after).or_default() += 1;
+            }
+        }
+
+        let stable_removes: BTreeSet<ElementId> = self
+            .removes
+            .iter()
+            .filter(|remove| stable.contains(&remove.at))
+            .map(|remove| remove.id)
+            .collect();
+
+        // Elements always come after older ones, so going newest first drops
+        // a whole run of removed elements in one pass.
+        let mut dropped = BTreeSet::new();
+        for insert in self.inserts.iter().rev() {
+            if stable_removes.contains(&insert.id)
+                && followers.get(&insert.id).copied().unwrap_or(0) == 0
+            {
+                dropped.insert(insert.id);
+                if let Some(after) = insert.after
+                    && let Some(count) = followers.get_mut(&after)
+                {
+                    *count -= 1;
+                }
+            }
+        }
+
+        self.inserts.retain(|insert| !dropped.contains(&insert.id));
+        self.removes.retain(|remove| !dropped.contains(&remove.id));
+
+        dropped.len()
+    }
+}
+
+impl<T: Ord> Merge for RGA<T> {
+    #[tracing::instrument(name = "RGA::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, mut other: Self) {
+        if other.inserts.is_empty() && other.removes.is_empty() {
+            return;
+        }
+
+        self.inserts.append(&mut other.inserts);
+        self.removes.append(&mut other.removes);
+        self.last = OnceLock::new();
+    }
+}
+
+impl<T: Ord> Default for RGA<T> {
+    fn default() -> Self {
+        RGA {
+            inserts: BTreeSet::new(),
+            removes: BTreeSet::new(),
+            last: OnceLock::new(),
+        }
+    }
+}
+
+impl<T: Ord> Clocked for RGA<T> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.inserts.iter().for_each(|insert| f(&insert.id.clock));
+        self.removes.iter().for_each(|remove| f(&remove.at));
+    }
+}
+
+impl<T: Ord + Clone> Delta for RGA<T> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let delta = RGA {
+            inserts: self
+                .inserts
+                .iter()
+                .filter(|insert| !seen.contains(&insert.id.clock))
+                .cloned()
+                .collect(),
+            removes: self
+                .removes
+                .iter()
+                .filter(|remove| !seen.contains(&remove.at))
+                .copied()
+                .collect(),
+            last: OnceLock::new(),
+        };
+
+        (delta != RGA::default()).then_some(delta)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+type Edits<T> = Vec<(HybridLogicalClock, proptest::sample::Index, T, bool)>;
+
+#[cfg(any(test, feature = "testing"))]
+impl<T: Ord + Arbitrary> Arbitrary for RGA<T> {
+    type Parameters = ParamsFor<T>;
+
+    type Strategy = proptest::strategy::Map<
+        proptest::collection::VecStrategy<(
+            StrategyFor<HybridLogicalClock>,
+            StrategyFor<proptest::sample::Index>,
+            T::Strategy,
+            StrategyFor<bool>,
+        )>,
+        fn(Edits<T>) -> Self,
+    >;
+
+    fn arbitrary_with(params: Self::Parameters) -> Self::Strategy {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        proptest::strategy::Strategy::prop_map(
+            vec(
+                (
+                    any::<HybridLogicalClock>(),
+                    any::<proptest::sample::Index>(),
+                    any_with::<T>(params),
+                    any::<bool>(),
+                ),
+                0..5,
+            ),
+            |mut edits| {
+                // Like a real replica, only insert after elements that are
+                // older than the insert.
+                edits.sort_by_key(|(clock, ..)| *clock);
+                edits.dedup_by_key(|(clock, ..)| *clock);
+
+                let mut rga = Self::default();
+                let mut ids = vec![None];
+                for (clock, after, value, removed) in edits {
+                    rga.insert_after(*after.get(&ids), value, clock.into());
+                    ids.push(Some(clock.into()));
+
+                    if removed {
+                        rga.remove(&clock.into(), clock);
+                    }
+                }
+
+                rga
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::delta;
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(merge_laws, RGA<u8>, default);
+
+    fn values(rga: &RGA<char>) -> String {
+        rga.iter().map(|(_, value)| value).collect()
+    }
+
+    /// Type `text` one character after another, starting after `after`.
+    fn type_after(
+        rga: &mut RGA<char>,
+        after: Option<ElementId>,
+        text: &str,
+        clocks: &mut impl Iterator<Item = HybridLogicalClock>,
+    ) {
+        let mut after = after;
+        for c in text.chars() {
+            let clock = clocks.next().unwrap();
+            rga.insert_after(after, c, clock.into());
+            after = Some(clock.into());
+        }
+    }
+
+    fn clocks(node: u128) -> impl Iterator<Item = HybridLogicalClock> {
+        use crate::crdt::hlc::ManualClock;
+        use chrono::{TimeZone, Utc};
+
+        let time = ManualClock::new(Utc.timestamp_opt(0, 0).unwrap());
+        let mut clock = HybridLogicalClock::new(uuid::Uuid::from_u128(node), &time);
+
+        std::iter::repeat_with(move || {
+            clock.tick(&time);
+            clock
+        })
+    }
+
+    #[test]
+    fn inserts_in_order() {
+        let mut rga = RGA::default();
+        let mut clocks = clocks(1);
+        type_after(&mut rga, None, "hello", &mut clocks);
+
+        assert_eq!(values(&rga), "hello");
+    }
+
+    #[test]
+    fn concurrent_runs_do_not_interleave() {
+        let mut base = RGA::default();
+        type_after(&mut base, None, "ab", &mut clocks(0));
+        let a = *base.iter().next().unwrap().0;
+
+        let mut left = base.clone();
+        type_after(&mut left, Some(a), "123", &mut clocks(1).skip(5));
+
+        let mut right = base.clone();
+        type_after(&mut right, Some(a), "xyz", &mut clocks(2).skip(5));
+
+        let merged = values(&left.merge(right));
+        assert!(merged == "axyz123b" || merged == "a123xyzb", "{merged}");
+    }
+
+    #[test]
+    fn removed_elements_keep_their_followers() {
+        let mut rga = RGA::default();
+        let mut clocks = clocks(1);
+        type_after(&mut rga, None, "abc", &mut clocks);
+
+        let b = *rga.iter().nth(1).unwrap().0;
+        rga.remove(&b, clocks.next().unwrap());
+
+        assert_eq!(values(&rga), "ac");
+    }
+
+    #[test]
+    fn gc_drops_stable_tombstones_nothing_follows() {
+        let mut rga = RGA::default();
+        let mut clocks = clocks(1);
+        type_after(&mut rga, None, "abcd", &mut clocks);
+
+        let ids: Vec<ElementId> = rga.iter().map(|(id, _)| *id).collect();
+        let removed = clocks.next().unwrap();
+        for id in &ids[1..] {
+            rga.remove(id, removed);
+        }
+        type_after(&mut rga, Some(ids[1]), "x", &mut clocks);
+
+        let mut stable = VersionVector::default();
+        stable.observe(removed);
+
+        // `b` still has `x` after it, but `c` and `d` can go.
+        assert_eq!(rga.gc(&stable), 2);
+        assert_eq!(values(&rga), "ax");
+        assert_eq!(rga.inserts.len(), 3);
+    }
+
+    proptest! {
+        #[test]
+        fn last_is_the_last_element(a: RGA<u8>, b: RGA<u8>, value: u8) {
+            let mut rga = a.clone();
+            assert_eq!(rga.last(), rga.iter().last().map(|(id, _)| *id));
+
+            rga.merge_mut(b);
+            assert_eq!(rga.last(), rga.iter().last().map(|(id, _)| *id));
+
+            if let Some(newest) = rga.max_clock() {
+                let id = ElementId::new(newest, u32::MAX);
+                rga.insert_after(rga.last(), value, id);
+                assert_eq!(rga.last(), Some(id));
+                assert_eq!(rga.last(), rga.iter().last().map(|(id, _)| *id));
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn gc_keeps_the_order(v: RGA<u8>) {
+            let mut stable = VersionVector::default();
+            v.each_clock(&mut |clock| stable.observe(*clock));
+
+            let mut collected = v.clone();
+            collected.gc(&stable);
+
+            let order = |rga: &RGA<u8>| rga.iter().map(|(id, v)| (*id, *v)).collect::<Vec<_>>();
+            assert_eq!(order(&collected), order(&v));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn merged_order_is_the_same_either_way(a: RGA<u8>, b: RGA<u8>) {
+            let ab: Vec<_> = a.clone().merge(b.clone()).iter().map(|(id, v)| (*id, *v)).collect();
+            let ba: Vec<_> = b.merge(a).iter().map(|(id, v)| (*id, *v)).collect();
+
+            assert_eq!(ab, ba);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: RGA<u8>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: RGA<u8>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
+}
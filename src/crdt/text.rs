@@ -0,0 +1,131 @@
+use super::{Clocked, Delta, ElementId, HybridLogicalClock, Merge, RGA, VersionVector};
+use similar::{ChangeTag, TextDiff};
+use std::fmt;
+
+/// Plain text that merges character by character, so concurrent edits to
+/// different parts of it are all kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
+pub struct Text(RGA<char>);
+
+impl Text {
+    /// Text typed all at once at `clock`. Making text from the same string at
+    /// the same clock always gives the same characters, so two replicas doing
+    /// it concurrently don't duplicate the text when they merge.
+    #[tracing::instrument(name = "Text::new", skip(clock))]
+    pub fn new(text: &str, clock: HybridLogicalClock) -> Self {
+        let mut new = Self::default();
+        new.edit(text, clock);
+
+        new
+    }
+
+    /// Change the text to `new` by inserting and removing as few characters
+    /// as possible.
+    #[tracing::instrument(name = "Text::edit", skip(self, clock))]
+    pub fn edit(&mut self, new: &str, clock: HybridLogicalClock) {
+        let elements: Vec<(ElementId, char)> = self.0.iter().map(|(id, c)| (*id, *c)).collect();
+        let old: String = elements.iter().map(|(_, c)| c).collect();
+
+        let mut elements = elements.into_iter();
+        let mut after = None;
+        let mut offset = 0;
+        for change in TextDiff::from_chars(old.as_str(), new).iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Equal => {
+                    after = elements.next().map(|(id, _)| id);
+                }
+                ChangeTag::Delete => {
+                    if let Some((id, _)) = elements.next() {
+                        self.0.remove(&id, clock);
+                    }
+                }
+                ChangeTag::Insert => {
+                    for c in change.value().chars() {
+                        let id = ElementId::new(clock, offset);
+                        offset += 1;
+
+                        self.0.insert_after(after, c, id);
+                        after = Some(id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Merge for Text {
+    #[tracing::instrument(name = "Text::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        self.0.merge_mut(other.0);
+    }
+}
+
+impl Clocked for Text {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.each_clock(f);
+    }
+}
+
+impl Delta for Text {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        self.0.delta_since(seen).map(Text)
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|(_, c)| fmt::Write::write_char(f, *c))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    crate::merge_laws!(merge_laws, Text, default);
+
+    fn clock(node: u128, timestamp: i64) -> HybridLogicalClock {
+        use super::super::hlc::ManualClock;
+        use chrono::{TimeZone, Utc};
+
+        let time = ManualClock::new(Utc.timestamp_opt(timestamp, 0).unwrap());
+
+        HybridLogicalClock::new(Uuid::from_u128(node), &time)
+    }
+
+    proptest! {
+        #[test]
+        fn edit_gives_the_new_text(old in "[ab ]{0,8}", new in "[ab ]{0,8}") {
+            let mut text = Text::new(&old, clock(0, 0));
+            text.edit(&new, clock(0, 1));
+
+            assert_eq!(text.to_string(), new);
+        }
+    }
+
+    #[test]
+    fn concurrent_edits_to_different_words_are_kept() {
+        let base = Text::new("the quick fox", clock(0, 0));
+
+        let mut slow = base.clone();
+        slow.edit("the slow fox", clock(1, 1));
+
+        let mut brown = base;
+        brown.edit("the quick brown fox", clock(2, 1));
+
+        assert_eq!(slow.merge(brown).to_string(), "the slow brown fox");
+    }
+
+    #[test]
+    fn making_the_same_text_concurrently_does_not_duplicate_it() {
+        let a = Text::new("walk dog", clock(0, 0));
+        let b = Text::new("walk dog", clock(0, 0));
+
+        assert_eq!(a.merge(b).to_string(), "walk dog");
+    }
+}
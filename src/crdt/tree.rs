@@ -0,0 +1,169 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Every move made to one node of a tree, so the whole tree can be replayed
+/// by `Tree`. A node with no moves is at the top level.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
+pub struct Parent<K: Ord>(BTreeSet<(HybridLogicalClock, Option<K>)>);
+
+impl<K: Ord> Parent<K> {
+    /// Move the node under `parent`, or to the top level if it's `None`.
+    #[tracing::instrument(name = "Parent::set", skip(self, parent, clock))]
+    pub fn set(&mut self, parent: Option<K>, clock: HybridLogicalClock) {
+        self.0.insert((clock, parent));
+    }
+}
+
+impl<K: Ord> Default for Parent<K> {
+    fn default() -> Self {
+        Parent(BTreeSet::new())
+    }
+}
+
+impl<K: Ord> Merge for Parent<K> {
+    #[tracing::instrument(name = "Parent::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, mut other: Self) {
+        self.0.append(&mut other.0);
+    }
+}
+
+impl<K: Ord> Clocked for Parent<K> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.iter().for_each(|(clock, _)| f(clock));
+    }
+}
+
+impl<K: Ord + Clone> Delta for Parent<K> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let moves: BTreeSet<_> = self
+            .0
+            .iter()
+            .filter(|(clock, _)| !seen.contains(clock))
+            .cloned()
+            .collect();
+
+        (!moves.is_empty()).then_some(Parent(moves))
+    }
+}
+
+/// A tree assembled from the moves of its nodes. Every move is replayed in
+/// clock order, skipping moves under a node that isn't in the tree and moves
+/// that would put a node under itself. Replicas that have seen the same moves
+/// build the same tree, and it never has a cycle, even when two replicas
+/// concurrently move nodes under each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tree<K: Ord> {
+    parents: BTreeMap<K, K>,
+}
+
+impl<K: Ord + Clone> Tree<K> {
+    #[tracing::instrument(name = "Tree::new", skip(nodes))]
+    pub fn new<'a>(nodes: impl IntoIterator<Item = (&'a K, &'a Parent<K>)>) -> Self
+    where
+        K: 'a,
+    {
+        let mut moves = Vec::new();
+        let mut keys = BTreeSet::new();
+        for (node, parent) in nodes {
+            keys.insert(node);
+            moves.extend(parent.0.iter().map(|(clock, parent)| (clock, node, parent)));
+        }
+        moves.sort();
+
+        let mut tree = Tree {
+            parents: BTreeMap::new(),
+        };
+        for (_, node, parent) in moves {
+            match parent {
+                Some(parent) if !keys.contains(parent) || tree.is_within(parent, node) => {}
+                Some(parent) => {
+                    tree.parents.insert(node.clone(), parent.clone());
+                }
+                None => {
+                    tree.parents.remove(node);
+                }
+            }
+        }
+
+        tree
+    }
+
+    pub fn parent(&self, node: &K) -> Option<&K> {
+        self.parents.get(node)
+    }
+
+    /// Whether `node` is `ancestor` or somewhere underneath it.
+    #[tracing::instrument(name = "Tree::is_within", skip(self, node, ancestor))]
+    pub fn is_within(&self, node: &K, ancestor: &K) -> bool {
+        let mut current = Some(node);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
+            }
+
+            current = self.parent(node);
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::delta;
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(merge_laws, Parent<u8>, default);
+
+    proptest! {
+        #[test]
+        fn merged_trees_have_no_cycles(
+            a in proptest::collection::btree_map(0..4u8, any::<Parent<u8>>(), 0..4),
+            b in proptest::collection::btree_map(0..4u8, any::<Parent<u8>>(), 0..4),
+        ) {
+            let mut merged = a;
+            for (node, parent) in b {
+                merged.entry(node).or_default().merge_mut(parent);
+            }
+
+            let tree = Tree::new(&merged);
+            for node in merged.keys() {
+                // Walking up from any node reaches the top within as many
+                // steps as there are nodes.
+                let mut current = Some(node);
+                for _ in 0..=merged.len() {
+                    current = current.and_then(|node| tree.parent(node));
+                }
+                assert_eq!(current, None);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_moves_under_each_other_keep_one(first: HybridLogicalClock, second: HybridLogicalClock) {
+            prop_assume!(first < second);
+
+            let mut a = Parent::default();
+            let mut b = Parent::default();
+
+            // One replica moves a under b while another moves b under a.
+            a.set(Some('b'), first);
+            b.set(Some('a'), second);
+
+            let nodes = BTreeMap::from([('a', a), ('b', b)]);
+            let tree = Tree::new(&nodes);
+            assert_eq!(tree.parent(&'a'), Some(&'b'));
+            assert_eq!(tree.parent(&'b'), None);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: Parent<u8>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
+}
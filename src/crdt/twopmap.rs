@@ -1,24 +1,26 @@
-use super::Merge;
-use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use std::collections::{BTreeMap, btree_map::Entry};
 use std::fmt::Debug;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 use proptest::arbitrary::{Arbitrary, ParamsFor, StrategyFor};
 
 #[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TwoPMap<K: Ord + Debug + Clone, V: Merge> {
     adds: BTreeMap<K, V>,
-    removes: BTreeSet<K>,
+    removes: BTreeMap<K, HybridLogicalClock>,
 }
 
 impl<K: Ord + Debug + Clone, V: Merge> TwoPMap<K, V> {
     pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
-        self.adds.iter().filter(|(k, _)| !self.removes.contains(k))
+        self.adds
+            .iter()
+            .filter(|(k, _)| !self.removes.contains_key(k))
     }
 
     #[tracing::instrument(name = "TwoPMap::insert", skip(self, value))]
     pub fn insert(&mut self, key: K, value: V) {
-        if self.removes.contains(&key) {
+        if self.removes.contains_key(&key) {
             return;
         }
 
@@ -32,50 +34,67 @@ impl<K: Ord + Debug + Clone, V: Merge> TwoPMap<K, V> {
         }
     }
 
-    #[tracing::instrument(name = "TwoPMap::remove", skip(self))]
-    pub fn remove(&mut self, key: K) {
+    #[tracing::instrument(name = "TwoPMap::remove", skip(self, clock))]
+    pub fn remove(&mut self, key: K, clock: HybridLogicalClock) {
         self.adds.remove(&key);
-        self.removes.insert(key);
+
+        let removed_at = self.removes.entry(key).or_insert(clock);
+        *removed_at = clock.max(*removed_at);
     }
 
     #[tracing::instrument(name = "TwoPMap::get_mut", skip(self))]
     pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        if self.removes.contains(key) {
+        if self.removes.contains_key(key) {
             return None;
         }
 
         self.adds.get_mut(key)
     }
 
-    #[tracing::instrument(name = "TwoPMap::retain", skip(self, decider))]
-    pub fn retain(&mut self, decider: impl Fn(&K, &V) -> bool) {
-        let mut to_remove = BTreeSet::new();
+    #[tracing::instrument(name = "TwoPMap::retain", skip(self, clock, decider))]
+    pub fn retain(&mut self, clock: HybridLogicalClock, decider: impl Fn(&K, &V) -> bool) {
+        let mut to_remove = Vec::new();
 
         for (k, v) in self.adds.iter() {
             if !decider(k, v) {
-                to_remove.insert(k.clone());
+                to_remove.push(k.clone());
             }
         }
 
-        self.adds.retain(|k, _| !to_remove.contains(k));
-        self.removes.append(&mut to_remove);
+        for k in to_remove {
+            self.remove(k, clock);
+        }
 
         // In unstable Rust:
         // self.adds
         //     .extract_if(decider)
         //     .for_each(|k, _| self.removes.insert(k));
     }
+
+    /// Forget tombstones for removes every replica has seen. Returns the
+    /// number of tombstones dropped.
+    #[tracing::instrument(name = "TwoPMap::gc", skip(self, stable))]
+    pub fn gc(&mut self, stable: &VersionVector) -> usize {
+        let before = self.removes.len();
+
+        self.removes.retain(|_, clock| !stable.contains(clock));
+
+        before - self.removes.len()
+    }
 }
 
 impl<K: Ord + Debug + Clone, V: Merge> Merge for TwoPMap<K, V> {
     #[tracing::instrument(name = "TwoPMap::merge_mut", skip(self, other))]
-    fn merge_mut(&mut self, mut other: Self) {
-        self.removes.append(&mut other.removes);
+    fn merge_mut(&mut self, other: Self) {
+        for (key, clock) in other.removes {
+            let removed_at = self.removes.entry(key).or_insert(clock);
+            *removed_at = clock.max(*removed_at);
+        }
 
         for (key, value) in other.adds {
             self.insert(key, value);
         }
-        self.adds.retain(|k, _| !self.removes.contains(k))
+        self.adds.retain(|k, _| !self.removes.contains_key(k))
     }
 }
 
@@ -84,17 +103,45 @@ impl<K: Ord + Debug + Clone, V: Merge> Default for TwoPMap<K, V> {
     fn default() -> Self {
         TwoPMap {
             adds: BTreeMap::default(),
-            removes: BTreeSet::default(),
+            removes: BTreeMap::default(),
         }
     }
 }
 
-#[cfg(test)]
+impl<K: Ord + Debug + Clone, V: Merge + Clocked> Clocked for TwoPMap<K, V> {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.adds.values().for_each(|value| value.each_clock(f));
+        self.removes.values().for_each(f);
+    }
+}
+
+impl<K: Ord + Debug + Clone, V: Delta> Delta for TwoPMap<K, V> {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let adds: BTreeMap<K, V> = self
+            .adds
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.delta_since(seen)?)))
+            .collect();
+
+        let removes: BTreeMap<K, HybridLogicalClock> = self
+            .removes
+            .iter()
+            .filter(|(_, clock)| !seen.contains(clock))
+            .map(|(key, clock)| (key.clone(), *clock))
+            .collect();
+
+        (!adds.is_empty() || !removes.is_empty()).then_some(TwoPMap { adds, removes })
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
 impl<K: Ord + Debug + Clone + Arbitrary, V: Merge + Arbitrary> Arbitrary for TwoPMap<K, V> {
     type Parameters = (ParamsFor<K>, ParamsFor<V>);
 
-    type Strategy =
-        proptest::strategy::Map<StrategyFor<Vec<(K, V, bool)>>, fn(Vec<(K, V, bool)>) -> Self>;
+    type Strategy = proptest::strategy::Map<
+        StrategyFor<Vec<(K, V, Option<HybridLogicalClock>)>>,
+        fn(Vec<(K, V, Option<HybridLogicalClock>)>) -> Self,
+    >;
 
     fn arbitrary_with(params: Self::Parameters) -> Self::Strategy {
         use proptest::collection::vec;
@@ -107,7 +154,7 @@ impl<K: Ord + Debug + Clone + Arbitrary, V: Merge + Arbitrary> Arbitrary for Two
                 (
                     any_with::<K>(k_param),
                     any_with::<V>(v_param),
-                    any::<bool>(),
+                    any::<Option<HybridLogicalClock>>(),
                 ),
                 1..4,
             ),
@@ -117,8 +164,8 @@ impl<K: Ord + Debug + Clone + Arbitrary, V: Merge + Arbitrary> Arbitrary for Two
                 for (key, value, removed) in items {
                     map.insert(key.clone(), value);
 
-                    if removed {
-                        map.remove(key);
+                    if let Some(clock) = removed {
+                        map.remove(key, clock);
                     }
                 }
 
@@ -131,7 +178,7 @@ impl<K: Ord + Debug + Clone + Arbitrary, V: Merge + Arbitrary> Arbitrary for Two
 #[cfg(test)]
 mod test {
     use super::super::max::Max;
-    use super::super::merge;
+    use super::super::{LWWRegister, delta, merge};
     use super::*;
     use proptest::proptest;
 
@@ -155,4 +202,34 @@ mod test {
             merge::test_associative(a, b, c);
         }
     }
+
+    crate::merge_laws!(merge_laws, TwoPMap<bool, Max<bool>>, default);
+
+    proptest! {
+        #[test]
+        fn gc_drops_stable_tombstones(v: TwoPMap<bool, Max<bool>>) {
+            let mut stable = VersionVector::default();
+            for clock in v.removes.values() {
+                stable.observe(*clock);
+            }
+
+            let mut collected = v.clone();
+            assert_eq!(collected.gc(&stable), v.removes.len());
+            assert_eq!(collected.iter().collect::<Vec<_>>(), v.iter().collect::<Vec<_>>());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_from_nothing(v: TwoPMap<bool, LWWRegister<bool>>) {
+            delta::test_delta_from_nothing(v);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn delta_is_contained(v: TwoPMap<bool, LWWRegister<bool>>, seen: VersionVector) {
+            delta::test_delta_is_contained(v, seen);
+        }
+    }
 }
@@ -0,0 +1,298 @@
+use super::{Clocked, Delta, HybridLogicalClock, Merge, VersionVector};
+use ciborium::Value;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::collections::{BTreeMap, btree_map::Entry};
+
+#[cfg(any(test, feature = "testing"))]
+use proptest::arbitrary::Arbitrary;
+
+/// Fields that a newer version of this program added to a struct, kept so
+/// that loading, merging, and storing with an older version doesn't lose
+/// them. We can't know how the newer version merges a field, so each one is
+/// a last-writer-wins register whose clock is the newest clock anywhere in
+/// it. Ties (including fields with no clocks at all) go to the value that
+/// encodes to the greater bytes, so every replica keeps the same one.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct UnknownFields(BTreeMap<String, Unknown>);
+
+impl UnknownFields {
+    pub fn insert(&mut self, name: String, value: Unknown) {
+        self.0.insert(name, value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Unknown)> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The fields with no clocks in them. There's no telling whether another
+    /// replica has seen these, so a delta of anything they're in has to
+    /// carry them.
+    pub fn clockless(&self) -> Self {
+        let fields = self
+            .0
+            .iter()
+            .filter(|(_, value)| value.max_clock().is_none())
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        UnknownFields(fields)
+    }
+}
+
+/// Whether `ours` should be kept over `theirs`. Values are compared by their
+/// encoding, since they can hold floats (including NaN) that don't have an
+/// order of their own.
+fn wins(ours: &Unknown, theirs: &Unknown) -> bool {
+    ours.max_clock()
+        .cmp(&theirs.max_clock())
+        .then_with(|| encode(ours).cmp(&encode(theirs)))
+        .is_ge()
+}
+
+fn encode(value: &Unknown) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes).expect("unknown values can always be encoded");
+
+    bytes
+}
+
+impl Merge for UnknownFields {
+    fn merge_mut(&mut self, other: Self) {
+        for (name, theirs) in other.0 {
+            match self.0.entry(name) {
+                Entry::Vacant(entry) => {
+                    entry.insert(theirs);
+                }
+                Entry::Occupied(mut entry) => {
+                    if !wins(entry.get(), &theirs) {
+                        entry.insert(theirs);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Clocked for UnknownFields {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        for value in self.0.values() {
+            value.each_clock(f);
+        }
+    }
+}
+
+impl Delta for UnknownFields {
+    /// The fields with clocks `seen` doesn't cover, along with every field
+    /// without clocks if there are any of those. Whatever contains these
+    /// fields has to add the clockless ones to its own deltas too.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let mut fields: BTreeMap<String, Unknown> = self
+            .0
+            .iter()
+            .filter(|(_, value)| value.max_clock().is_some() && !seen.covers(*value))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        if fields.is_empty() {
+            return None;
+        }
+
+        fields.extend(self.clockless().0);
+        Some(UnknownFields(fields))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Arbitrary for UnknownFields {
+    type Parameters = ();
+
+    type Strategy = proptest::strategy::BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        use proptest::collection::{btree_map, vec};
+        use proptest::prelude::*;
+
+        let value = prop_oneof![
+            any::<HybridLogicalClock>().prop_map(Unknown::Clock),
+            any::<u8>().prop_map(|n| Unknown::Other(Value::from(n))),
+        ];
+
+        btree_map("[a-c]", vec(value, 0..3).prop_map(Unknown::Array), 0..3)
+            .prop_map(UnknownFields)
+            .boxed()
+    }
+}
+
+/// The value of a field we don't know. Clocks in it are picked out, so a
+/// replica sees them like any others and they're written against the node
+/// table of wherever they're written next (see `NodeTable::scope`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unknown {
+    Clock(HybridLogicalClock),
+    Array(Vec<Unknown>),
+    Map(Vec<(Unknown, Unknown)>),
+    Other(Value),
+}
+
+impl Unknown {
+    /// Pick the clocks out of `value`. Clocks that refer to a node table
+    /// need it to be current (see `NodeTable::scope`).
+    pub fn new(value: Value) -> Result<Self, ciborium::value::Error> {
+        if is_clock(&value) {
+            return Ok(Unknown::Clock(value.deserialized()?));
+        }
+
+        Ok(match value {
+            Value::Array(values) => Unknown::Array(
+                values
+                    .into_iter()
+                    .map(Unknown::new)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Map(entries) => Unknown::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((Unknown::new(key)?, Unknown::new(value)?)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            value => Unknown::Other(value),
+        })
+    }
+}
+
+/// Whether `value` is a map with exactly the fields of a clock.
+fn is_clock(value: &Value) -> bool {
+    let Value::Map(entries) = value else {
+        return false;
+    };
+
+    let mut fields: Vec<&str> = entries
+        .iter()
+        .filter_map(|(key, _)| key.as_text())
+        .collect();
+    fields.sort_unstable();
+
+    fields == ["counter", "node_id", "timestamp"]
+}
+
+impl Clocked for Unknown {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        match self {
+            Unknown::Clock(clock) => f(clock),
+            Unknown::Array(values) => values.iter().for_each(|value| value.each_clock(f)),
+            Unknown::Map(entries) => entries.iter().for_each(|(key, value)| {
+                key.each_clock(f);
+                value.each_clock(f);
+            }),
+            Unknown::Other(_) => {}
+        }
+    }
+}
+
+impl Serialize for Unknown {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Unknown::Clock(clock) => clock.serialize(serializer),
+            Unknown::Array(values) => serializer.collect_seq(values),
+            Unknown::Map(entries) => {
+                serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+            }
+            Unknown::Other(value) => value.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Unknown {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Unknown::new(Value::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::NodeTable;
+    use super::super::hlc::WallClock;
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    crate::merge_laws!(merge_laws, UnknownFields, default);
+
+    #[test]
+    fn clocks_are_written_against_the_current_node_table() {
+        let clock = HybridLogicalClock::new(Uuid::from_u128(7), &WallClock);
+        let table = NodeTable::new(vec![Uuid::nil(), Uuid::from_u128(7)]);
+
+        let (table, stored) = table.scope(|| json!({ "set": [clock], "name": "x" }));
+        assert_eq!(stored["set"][0]["node_id"], 1);
+
+        let (_, unknown) = table.scope(|| serde_json::from_value::<Unknown>(stored).unwrap());
+        assert_eq!(unknown.max_clock(), Some(clock));
+
+        let (_, written) = NodeTable::new(vec![Uuid::from_u128(7)])
+            .scope(|| serde_json::to_value(&unknown).unwrap());
+        assert_eq!(written["set"][0]["node_id"], 0);
+        assert_eq!(written["name"], "x");
+    }
+
+    #[test]
+    fn the_field_with_the_newest_clock_wins() {
+        let older = HybridLogicalClock::new(Uuid::nil(), &WallClock);
+        let mut newer = older;
+        newer.tick(&WallClock);
+
+        let field = |clock| {
+            let mut fields = UnknownFields::default();
+            fields.insert(
+                "labels".into(),
+                Unknown::Array(vec![Unknown::Other(Value::from(1)), Unknown::Clock(clock)]),
+            );
+            fields
+        };
+
+        assert_eq!(field(older).merge(field(newer)), field(newer));
+        assert_eq!(field(newer).merge(field(older)), field(newer));
+    }
+
+    #[test]
+    fn ties_go_the_same_way_on_every_replica() {
+        let field = |value: f64| {
+            let mut fields = UnknownFields::default();
+            fields.insert("weight".into(), Unknown::Other(Value::Float(value)));
+            fields
+        };
+        let encoded = |fields: UnknownFields| {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&fields, &mut bytes).unwrap();
+            bytes
+        };
+
+        let a = field(f64::NAN);
+        let b = field(1.0);
+        assert_eq!(encoded(a.clone().merge(b.clone())), encoded(b.merge(a)));
+    }
+
+    #[test]
+    fn clockless_fields_go_along_with_any_delta() {
+        let clock = HybridLogicalClock::new(Uuid::nil(), &WallClock);
+        let mut fields = UnknownFields::default();
+        fields.insert("theme".into(), Unknown::Other(Value::from("dark")));
+        fields.insert("labels".into(), Unknown::Clock(clock));
+
+        assert_eq!(
+            fields.delta_since(&VersionVector::default()),
+            Some(fields.clone())
+        );
+
+        let mut seen = VersionVector::default();
+        seen.observe(clock);
+        assert_eq!(fields.delta_since(&seen), None);
+        assert_eq!(fields.clockless().iter().count(), 1);
+    }
+}
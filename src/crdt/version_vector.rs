@@ -0,0 +1,210 @@
+use super::{Clocked, HybridLogicalClock, Merge};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeSeq;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+/// The latest clock we have seen from each replica.
+///
+/// Serialized, it's just a list of clocks, since each clock already names
+/// its replica (and inside a document, a node table numbers them). Versions
+/// from before node tables wrote a map from replica to clock, which is still
+/// accepted when reading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "testing"), derive(proptest_derive::Arbitrary))]
+pub struct VersionVector(
+    #[cfg_attr(
+        any(test, feature = "testing"),
+        proptest(strategy = "clocks_strategy()")
+    )]
+    BTreeMap<Uuid, HybridLogicalClock>,
+);
+
+#[cfg(any(test, feature = "testing"))]
+fn clocks_strategy() -> impl proptest::strategy::Strategy<Value = BTreeMap<Uuid, HybridLogicalClock>>
+{
+    use proptest::prelude::*;
+
+    proptest::collection::vec(any::<HybridLogicalClock>(), 0..4).prop_map(|clocks| {
+        let mut vv = VersionVector::default();
+        for clock in clocks {
+            vv.observe(clock);
+        }
+
+        vv.0
+    })
+}
+
+impl serde::Serialize for VersionVector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut clocks = serializer.serialize_seq(Some(self.0.len()))?;
+        for clock in self.0.values() {
+            clocks.serialize_element(clock)?;
+        }
+        clocks.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VersionVector {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(VersionVectorVisitor)
+    }
+}
+
+struct VersionVectorVisitor;
+
+impl<'de> Visitor<'de> for VersionVectorVisitor {
+    type Value = VersionVector;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a map from replica to clock, or a list of clocks")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut vv = VersionVector::default();
+        while let Some((node_id, clock)) = map.next_entry::<Uuid, HybridLogicalClock>()? {
+            vv.0.insert(node_id, clock);
+        }
+
+        Ok(vv)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut vv = VersionVector::default();
+        while let Some(clock) = seq.next_element()? {
+            vv.observe(clock);
+        }
+
+        Ok(vv)
+    }
+}
+
+impl VersionVector {
+    pub fn iter(&self) -> impl Iterator<Item = (&Uuid, &HybridLogicalClock)> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, node_id: &Uuid) -> Option<&HybridLogicalClock> {
+        self.0.get(node_id)
+    }
+
+    #[tracing::instrument(name = "VersionVector::observe", skip(self))]
+    pub fn observe(&mut self, clock: HybridLogicalClock) {
+        let entry = self.0.entry(clock.node_id()).or_insert(clock);
+        if clock > *entry {
+            *entry = clock;
+        }
+    }
+
+    /// Whether the event stamped with `clock` is included in this vector.
+    pub fn contains(&self, clock: &HybridLogicalClock) -> bool {
+        self.0
+            .get(&clock.node_id())
+            .is_some_and(|seen| clock <= seen)
+    }
+
+    /// Whether every clock in `value` is included in this vector.
+    pub fn covers(&self, value: &impl Clocked) -> bool {
+        let mut covered = true;
+        value.each_clock(&mut |clock| covered &= self.contains(clock));
+
+        covered
+    }
+
+    /// Whether we have seen everything `other` has seen.
+    pub fn dominates(&self, other: &Self) -> bool {
+        other.0.values().all(|clock| self.contains(clock))
+    }
+
+    /// The events both vectors have seen.
+    pub fn meet(&self, other: &Self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter_map(|(node_id, clock)| {
+                    other
+                        .0
+                        .get(node_id)
+                        .map(|theirs| (*node_id, *clock.min(theirs)))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Vectors are ordered by what they have seen: one is greater than another if
+/// it dominates it, and two vectors that each have seen something the other
+/// hasn't are concurrent (`None`.)
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.dominates(other), other.dominates(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Merge for VersionVector {
+    #[tracing::instrument(name = "VersionVector::merge_mut", skip(self, other))]
+    fn merge_mut(&mut self, other: Self) {
+        for clock in other.0.into_values() {
+            self.observe(clock);
+        }
+    }
+}
+
+impl Clocked for VersionVector {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.0.values().for_each(f);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    crate::merge_laws!(merge_laws, VersionVector, default);
+
+    proptest! {
+        #[test]
+        fn merged_dominates_both(a: VersionVector, b: VersionVector) {
+            let merged = a.clone().merge(b.clone());
+
+            assert!(merged.dominates(&a));
+            assert!(merged.dominates(&b));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn both_dominate_meet(a: VersionVector, b: VersionVector) {
+            let meet = a.meet(&b);
+
+            assert!(a.dominates(&meet));
+            assert!(b.dominates(&meet));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn merged_is_at_least_both(a: VersionVector, b: VersionVector) {
+            let merged = a.clone().merge(b.clone());
+
+            assert!(merged >= a);
+            assert!(merged >= b);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn order_is_antisymmetric(a: VersionVector, b: VersionVector) {
+            assert_eq!(a.partial_cmp(&b), b.partial_cmp(&a).map(Ordering::reverse));
+            assert_eq!(a.partial_cmp(&b) == Some(Ordering::Equal), a == b);
+        }
+    }
+}
@@ -1,32 +1,177 @@
 mod task;
 
-use crate::crdt::{HybridLogicalClock, Merge, TwoPMap};
+use crate::crdt::{
+    Clocked, Delta, ElementId, HybridLogicalClock, Merge, NodeTable, ORMap, RGA, Tree, Unknown,
+    UnknownFields, VersionVector,
+};
+use crate::ids::IdSource;
 use itertools::Itertools;
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 pub use task::Task;
 use uuid::Uuid;
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+/// Serialized, a document starts with a table of the replicas its clocks
+/// came from (`nodes`), and its clocks refer to replicas by their position in
+/// the table instead of repeating the whole UUID.
+#[derive(Debug, Clone, Default)]
 pub struct Document {
-    pub tasks: TwoPMap<Uuid, Task>,
+    pub tasks: ORMap<Uuid, Task>,
+
+    /// The order the user wants tasks listed in. Moving a task removes its
+    /// element and inserts a new one, so a task moved concurrently on two
+    /// replicas ends up with two elements; the newest one wins.
+    pub order: RGA<Uuid>,
+
+    /// Whatever newer versions added to the document, merged and written
+    /// back out along with everything else.
+    pub unknown: UnknownFields,
+
+    /// Tasks archived before tasks had clocks to remove them at, which left
+    /// only their ID behind. Merging drops these from `tasks`, so a replica
+    /// that hasn't seen them archived can't bring them back.
+    pub legacy_removes: BTreeSet<Uuid>,
+
+    /// The node table the document was read with, which writing it again
+    /// keeps so that clocks that didn't change are written the same way.
+    nodes: NodeTable,
 }
 
 impl Document {
+    /// Tasks in the user's order. Tasks added before ordering existed come
+    /// last, oldest first.
     #[tracing::instrument(name = "Document::tasks", skip(self))]
     pub fn tasks(&self) -> impl Iterator<Item = (&Uuid, &Task)> {
-        self.tasks
-            .iter()
-            .sorted_by_cached_key(|(_, task)| task.added.value())
+        let positions: HashMap<Uuid, usize> = self
+            .placements()
+            .into_iter()
+            .enumerate()
+            .map(|(position, (_, id))| (id, position))
+            .collect();
+
+        self.tasks.iter().sorted_by_cached_key(|(id, task)| {
+            (
+                positions.get(*id).copied().unwrap_or(usize::MAX),
+                task.added.value(),
+            )
+        })
+    }
+
+    /// The elements of `order` that place live tasks, in order.
+    fn placements(&self) -> Vec<(ElementId, Uuid)> {
+        let elements: Vec<(&ElementId, &Uuid)> = self.order.iter().collect();
+
+        let mut newest: HashMap<Uuid, ElementId> = HashMap::new();
+        for (element, id) in &elements {
+            let current = newest.entry(**id).or_insert(**element);
+            *current = (*current).max(**element);
+        }
+
+        elements
+            .into_iter()
+            .filter(|(element, id)| {
+                newest.get(*id) == Some(*element) && self.tasks.contains_key(id)
+            })
+            .map(|(element, id)| (*element, *id))
+            .collect()
+    }
+
+    /// How tasks are nested under each other.
+    #[tracing::instrument(name = "Document::tree", skip(self))]
+    pub fn tree(&self) -> Tree<Uuid> {
+        Tree::new(self.tasks.iter().map(|(id, task)| (id, &task.parent)))
+    }
+
+    #[tracing::instrument(name = "Document::task", skip(self))]
+    pub fn task(&self, id: &Uuid) -> Option<&Task> {
+        self.tasks.get(id)
     }
 
-    #[tracing::instrument(name = "Document::add_task", skip(self, clock))]
-    pub fn add_task(&mut self, description: String, clock: HybridLogicalClock) -> Uuid {
-        let id = Uuid::new_v4();
+    #[tracing::instrument(name = "Document::add_task", skip(self, clock, ids))]
+    pub fn add_task(
+        &mut self,
+        description: String,
+        clock: HybridLogicalClock,
+        ids: &dyn IdSource,
+    ) -> Uuid {
+        let id = ids.next_id();
 
-        self.tasks.insert(id, Task::new(description, clock));
+        self.tasks.insert(id, Task::new(description, clock), clock);
+
+        self.order.insert_after(self.order.last(), id, clock.into());
 
         id
     }
 
+    /// Move a task to just before `before`, or to the end if `before` is
+    /// `None`. Returns false if either task doesn't exist.
+    #[tracing::instrument(name = "Document::move_task", skip(self, id, before, clock))]
+    pub fn move_task(
+        &mut self,
+        id: &Uuid,
+        before: Option<&Uuid>,
+        clock: HybridLogicalClock,
+    ) -> bool {
+        if !self.tasks.contains_key(id)
+            || before.is_some_and(|before| !self.tasks.contains_key(before))
+        {
+            return false;
+        }
+
+        // Tasks without a position are listed after all the others, so
+        // moving in front of one moves to the end of the positioned tasks.
+        let placements = self.placements();
+        let end = before
+            .and_then(|before| placements.iter().position(|(_, placed)| placed == before))
+            .unwrap_or(placements.len());
+        let after = placements[..end]
+            .iter()
+            .rev()
+            .find(|(_, placed)| placed != id)
+            .map(|(element, _)| *element);
+
+        let elements: Vec<ElementId> = self
+            .order
+            .iter()
+            .filter(|(_, placed)| *placed == id)
+            .map(|(element, _)| *element)
+            .collect();
+        for element in elements {
+            self.order.remove(&element, clock);
+        }
+
+        self.order.insert_after(after, *id, clock.into());
+
+        true
+    }
+
+    /// Make a task a subtask of `parent`, or a top-level task if `parent` is
+    /// `None`. Returns false if either task doesn't exist, or if `parent` is
+    /// the task itself or one of its subtasks.
+    #[tracing::instrument(name = "Document::nest_task", skip(self, id, parent, clock))]
+    pub fn nest_task(
+        &mut self,
+        id: &Uuid,
+        parent: Option<&Uuid>,
+        clock: HybridLogicalClock,
+    ) -> bool {
+        if let Some(parent) = parent
+            && (!self.tasks.contains_key(parent) || self.tree().is_within(parent, id))
+        {
+            return false;
+        }
+
+        if let Some(task) = self.tasks.get_mut(id, clock) {
+            task.parent.set(parent.copied(), clock);
+
+            true
+        } else {
+            false
+        }
+    }
+
     #[tracing::instrument(name = "Document::update_task_description", skip(self, id, clock))]
     pub fn update_task_description(
         &mut self,
@@ -34,7 +179,7 @@ impl Document {
         description: String,
         clock: HybridLogicalClock,
     ) -> bool {
-        if let Some(task) = self.tasks.get_mut(id) {
+        if let Some(task) = self.tasks.get_mut(id, clock) {
             task.description.set(description, clock);
 
             true
@@ -43,10 +188,31 @@ impl Document {
         }
     }
 
-    #[tracing::instrument(name = "Document::complete_task", skip(self, id, clock))]
-    pub fn complete_task(&mut self, id: &Uuid, clock: HybridLogicalClock) -> bool {
-        if let Some(task) = self.tasks.get_mut(id) {
-            task.complete.set(true, clock);
+    #[tracing::instrument(name = "Document::edit_task_description", skip(self, id, clock))]
+    pub fn edit_task_description(
+        &mut self,
+        id: &Uuid,
+        description: &str,
+        clock: HybridLogicalClock,
+    ) -> bool {
+        if let Some(task) = self.tasks.get_mut(id, clock) {
+            task.edit_description(description, clock);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    #[tracing::instrument(name = "Document::set_task_complete", skip(self, id, clock))]
+    pub fn set_task_complete(
+        &mut self,
+        id: &Uuid,
+        complete: bool,
+        clock: HybridLogicalClock,
+    ) -> bool {
+        if let Some(task) = self.tasks.get_mut(id, clock) {
+            task.complete.set(complete, clock);
 
             true
         } else {
@@ -54,13 +220,404 @@ impl Document {
         }
     }
 
-    pub fn archive_completed_tasks(&mut self) {
-        self.tasks.retain(|_, task| !*task.complete.value());
+    /// Flip whether a task is complete. Returns the new state, or `None` if
+    /// there's no such task.
+    #[tracing::instrument(name = "Document::toggle_task", skip(self, id, clock))]
+    pub fn toggle_task(&mut self, id: &Uuid, clock: HybridLogicalClock) -> Option<bool> {
+        let task = self.tasks.get_mut(id, clock)?;
+        let complete = !task.complete.value();
+        task.complete.set(complete, clock);
+
+        Some(complete)
+    }
+
+    #[tracing::instrument(name = "Document::archive_completed_tasks", skip(self, clock))]
+    pub fn archive_completed_tasks(&mut self, clock: HybridLogicalClock) {
+        self.tasks.retain(clock, |_, task| !task.complete.value());
+
+        // Archived tasks don't need a place in the order any more, and
+        // removing them there lets `gc` drop the elements later.
+        let unplaced: Vec<ElementId> = self
+            .order
+            .iter()
+            .filter(|(_, id)| !self.tasks.contains_key(id))
+            .map(|(element, _)| *element)
+            .collect();
+        for element in unplaced {
+            self.order.remove(&element, clock);
+        }
+    }
+
+    #[tracing::instrument(name = "Document::gc", skip(self, stable))]
+    pub fn gc(&mut self, stable: &VersionVector) -> usize {
+        self.tasks.gc(stable) + self.order.gc(stable)
     }
 }
 
 impl Merge for Document {
     fn merge_mut(&mut self, other: Self) {
         self.tasks.merge_mut(other.tasks);
+        self.order.merge_mut(other.order);
+        self.unknown.merge_mut(other.unknown);
+        self.nodes.merge_mut(other.nodes);
+
+        self.legacy_removes.extend(other.legacy_removes);
+        for id in &self.legacy_removes {
+            self.tasks.forget(id);
+        }
+    }
+}
+
+impl Delta for Document {
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        let tasks = self.tasks.delta_since(seen);
+        let order = self.order.delta_since(seen);
+        let unknown = self.unknown.delta_since(seen);
+
+        if tasks.is_none() && order.is_none() && unknown.is_none() {
+            return None;
+        }
+
+        Some(Document {
+            tasks: tasks.unwrap_or_default(),
+            order: order.unwrap_or_default(),
+            unknown: unknown.unwrap_or_else(|| self.unknown.clockless()),
+            legacy_removes: self.legacy_removes.clone(),
+            nodes: NodeTable::default(),
+        })
+    }
+}
+
+impl Document {
+    /// Like `each_clock`, but also says where in the document each clock
+    /// lives, like `tasks.<id>.description`.
+    pub fn each_located_clock(&self, f: &mut dyn FnMut(String, &HybridLogicalClock)) {
+        for (id, task, clocks) in self.tasks.entries() {
+            clocks.for_each(|clock| f(format!("tasks.{id}"), clock));
+            task.each_field_clock(&mut |field, clock| f(format!("tasks.{id}.{field}"), clock));
+        }
+
+        self.order
+            .each_clock(&mut |clock| f("order".to_string(), clock));
+
+        for (field, value) in self.unknown.iter() {
+            value.each_clock(&mut |clock| f(field.clone(), clock));
+        }
+    }
+}
+
+impl Clocked for Document {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.tasks.each_clock(f);
+        self.order.each_clock(f);
+        self.unknown.each_clock(f);
+    }
+}
+
+impl Document {
+    /// The table we were read with, plus any replicas our clocks came from
+    /// that aren't in it yet.
+    fn node_table(&self) -> NodeTable {
+        let mut new = BTreeSet::new();
+        self.each_clock(&mut |clock| {
+            new.insert(clock.node_id());
+        });
+
+        let mut table = self.nodes.clone();
+        for node in new {
+            table.insert(node);
+        }
+
+        table
+    }
+}
+
+impl serde::Serialize for Document {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let table = self.node_table();
+
+        let mut document = serializer.serialize_map(None)?;
+        document.serialize_entry("nodes", table.nodes())?;
+        let (_, written) = table.scope(|| {
+            document.serialize_entry("tasks", &self.tasks)?;
+            document.serialize_entry("order", &self.order)?;
+            for (field, value) in self.unknown.iter() {
+                document.serialize_entry(field, value)?;
+            }
+
+            Ok(())
+        });
+        written?;
+        if !self.legacy_removes.is_empty() {
+            document.serialize_entry("legacy_removes", &self.legacy_removes)?;
+        }
+        document.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Document {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("Document", &["nodes", "tasks", "order"], DocumentVisitor)
+    }
+}
+
+struct DocumentVisitor;
+
+impl<'de> Visitor<'de> for DocumentVisitor {
+    type Value = Document;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a document")
+    }
+
+    /// The node table has to come before any clocks that use it. We always
+    /// write it first, and `nodes` sorts before the other fields in maps
+    /// that order their keys. Fields we don't know might sort before it, so
+    /// we look for their clocks once we've read everything.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut nodes = NodeTable::default();
+        let mut tasks = None;
+        let mut order = None;
+        let mut legacy_removes = BTreeSet::new();
+        let mut unknown = Vec::new();
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "nodes" => {
+                    nodes = NodeTable::new(map.next_value()?);
+                    continue;
+                }
+                "legacy_removes" => {
+                    legacy_removes.extend(map.next_value::<BTreeSet<Uuid>>()?);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let read;
+            (nodes, read) = nodes.scope(|| match key.as_str() {
+                "tasks" => map.next_value::<StoredTasks>().map(|read| {
+                    let (read, archived) = read.into_tasks();
+                    tasks = Some(read);
+                    legacy_removes.extend(archived);
+                }),
+                "order" => map.next_value().map(|read| order = Some(read)),
+                _ => map
+                    .next_value::<ciborium::Value>()
+                    .map(|read| unknown.push((key, read))),
+            });
+            read?;
+        }
+
+        let (nodes, fields) = nodes.scope(|| {
+            let mut fields = UnknownFields::default();
+            for (field, value) in unknown {
+                fields.insert(field, Unknown::new(value).map_err(de::Error::custom)?);
+            }
+
+            Ok(fields)
+        });
+
+        Ok(Document {
+            tasks: tasks.ok_or_else(|| de::Error::missing_field("tasks"))?,
+            order: order.unwrap_or_default(),
+            unknown: fields?,
+            legacy_removes,
+            nodes,
+        })
+    }
+}
+
+/// Documents used to keep tasks in a two-phase map, which removed archived
+/// tasks from `adds` and only remembered their IDs. We read those as tasks
+/// added at the clock they were added at, and removed (if they're in both)
+/// at their newest clock. Archived tasks that are only an ID have no clock
+/// to remove them at, so they're kept in `legacy_removes` instead.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StoredTasks {
+    Map(ORMap<Uuid, Task>),
+    TwoPhase {
+        adds: BTreeMap<Uuid, Task>,
+        removes: BTreeSet<Uuid>,
+    },
+}
+
+impl StoredTasks {
+    /// The tasks, and the IDs of archived tasks that are only an ID.
+    fn into_tasks(self) -> (ORMap<Uuid, Task>, BTreeSet<Uuid>) {
+        let (adds, mut removes) = match self {
+            StoredTasks::Map(tasks) => return (tasks, BTreeSet::new()),
+            StoredTasks::TwoPhase { adds, removes } => (adds, removes),
+        };
+
+        let mut tasks = ORMap::default();
+        for (id, task) in adds {
+            let added = *task.added.clock();
+            let newest = task.max_clock().unwrap_or(added);
+
+            tasks.insert(id, task, added);
+            if removes.remove(&id) {
+                tasks.remove(&id, newest);
+            }
+        }
+
+        (tasks, removes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdt::hlc::ManualClock;
+    use crate::ids::SeededIds;
+    use chrono::{DateTime, Utc};
+
+    fn document() -> Document {
+        let time = ManualClock::new(DateTime::<Utc>::UNIX_EPOCH);
+        let ids = SeededIds::new(0);
+        let mut document = Document::default();
+
+        for node in 1..=2 {
+            let clock = HybridLogicalClock::new(Uuid::from_u128(node), &time);
+            let id = document.add_task(format!("task {node}"), clock, &ids);
+            document.edit_task_description(&id, &format!("the task {node}"), clock);
+        }
+
+        document
+    }
+
+    #[test]
+    fn clocks_refer_to_the_node_table() {
+        let json = serde_json::to_value(document()).unwrap();
+
+        assert_eq!(
+            json["nodes"],
+            serde_json::json!([Uuid::from_u128(1), Uuid::from_u128(2)])
+        );
+        assert!(
+            !json["tasks"]
+                .to_string()
+                .contains(&Uuid::from_u128(1).to_string())
+        );
+
+        let decoded: Document = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(decoded).unwrap(), json);
+    }
+
+    #[test]
+    fn documents_without_a_node_table_still_load() {
+        let document = document();
+        let json = serde_json::json!({
+            "tasks": document.tasks,
+            "order": document.order,
+        });
+        assert!(
+            json["tasks"]
+                .to_string()
+                .contains(&Uuid::from_u128(1).to_string())
+        );
+
+        let decoded: Document = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(document).unwrap()
+        );
+    }
+
+    #[test]
+    fn tasks_stored_in_a_two_phase_map_still_load() {
+        let time = ManualClock::new(DateTime::<Utc>::UNIX_EPOCH);
+        let clock = HybridLogicalClock::new(Uuid::from_u128(1), &time);
+        let task = serde_json::to_value(Task::new("walk dog".into(), clock)).unwrap();
+        let (live, removed, archived) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+
+        let json = serde_json::json!({
+            "tasks": {
+                "adds": { live.to_string(): task, removed.to_string(): task },
+                "removes": [removed, archived],
+            },
+        });
+
+        let document: Document = serde_json::from_value(json).unwrap();
+        let ids: Vec<_> = document.tasks().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [live]);
+        assert_eq!(document.tasks.entries().count(), 2);
+        assert_eq!(document.legacy_removes, BTreeSet::from([archived]));
+    }
+
+    #[test]
+    fn merging_never_brings_back_tasks_archived_without_a_clock() {
+        let mut document = document();
+        let mut stale = document.clone();
+        let id = *document.tasks.iter().next().unwrap().0;
+        document.legacy_removes.insert(id);
+
+        document.merge_mut(stale.clone());
+        assert!(document.task(&id).is_none());
+
+        stale.merge_mut(document.delta_since(&VersionVector::default()).unwrap());
+        assert!(stale.task(&id).is_none());
+    }
+
+    #[test]
+    fn fields_without_clocks_go_along_with_any_delta() {
+        let mut json = serde_json::to_value(document()).unwrap();
+        json["theme"] = "dark".into();
+        let mut document: Document = serde_json::from_value(json).unwrap();
+
+        let mut seen = VersionVector::default();
+        document.each_clock(&mut |clock| seen.observe(*clock));
+        assert!(document.delta_since(&seen).is_none());
+
+        let time = ManualClock::new(DateTime::<Utc>::UNIX_EPOCH);
+        let clock = HybridLogicalClock::new(Uuid::from_u128(3), &time);
+        document.add_task("task 3".into(), clock, &SeededIds::new(3));
+
+        let delta = serde_json::to_value(document.delta_since(&seen).unwrap()).unwrap();
+        assert_eq!(delta["theme"], "dark");
+    }
+
+    #[test]
+    fn fields_from_newer_versions_survive_merging_and_storing() {
+        let mut json = serde_json::to_value(document()).unwrap();
+        let clock = serde_json::json!({
+            "timestamp": "2000-01-01T00:00:00Z",
+            "counter": 0,
+            "node_id": 1,
+        });
+
+        // `labels` sorts before the node table its clock refers to.
+        json["labels"] = serde_json::json!({ "context": [clock] });
+        let (id, task) = json["tasks"]["entries"]
+            .as_object_mut()
+            .unwrap()
+            .iter_mut()
+            .next()
+            .unwrap();
+        let id: Uuid = id.parse().unwrap();
+        task["value"]["priority"] = serde_json::json!({ "value": 3, "clock": clock });
+
+        // Merging in clocks from another replica changes the node table.
+        let mut document: Document = serde_json::from_value(json).unwrap();
+        let time = ManualClock::new(DateTime::<Utc>::UNIX_EPOCH);
+        let clock = HybridLogicalClock::new(Uuid::nil(), &time);
+        let mut other = Document::default();
+        other.add_task("task 0".into(), clock, &SeededIds::new(1));
+        document.merge_mut(other);
+
+        let stored = serde_json::to_value(&document).unwrap();
+        let node = |clock: &serde_json::Value| {
+            stored["nodes"][clock["node_id"].as_u64().unwrap() as usize].clone()
+        };
+        let two = serde_json::json!(Uuid::from_u128(2));
+        assert_eq!(node(&stored["labels"]["context"][0]), two);
+        let priority = &stored["tasks"]["entries"][id.to_string()]["value"]["priority"];
+        assert_eq!(priority["value"], 3);
+        assert_eq!(node(&priority["clock"]), two);
+
+        let decoded: Document = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(serde_json::to_value(decoded).unwrap(), stored);
     }
 }
@@ -1,38 +1,246 @@
-use crate::crdt::{hlc::HybridLogicalClock, LWWRegister, Merge};
+use crate::crdt::{
+    Clocked, Delta, EWFlag, LWWRegister, MVRegister, Merge, Parent, Text, UnknownFields,
+    VersionVector, hlc::HybridLogicalClock,
+};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
 use std::fmt;
+use uuid::Uuid;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Merge, serde::Serialize, serde::Deserialize)]
 pub struct Task {
     pub added: LWWRegister<DateTime<Utc>>,
-    pub complete: LWWRegister<bool>,
-    pub description: LWWRegister<String>,
+
+    /// Completing a task wins over reopening it concurrently on another
+    /// replica: if anyone finished it, it's done until someone who has seen
+    /// that reopens it.
+    #[serde(deserialize_with = "deserialize_complete")]
+    pub complete: EWFlag,
+
+    #[serde(deserialize_with = "deserialize_description")]
+    pub description: MVRegister<String>,
+
+    /// The description as text edited character by character, once someone
+    /// has edited it that way. Whichever of this and `description` changed
+    /// last is the one shown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<Text>,
+
+    /// The task this is a subtask of. Use `Document::tree` to read it, since
+    /// moves that would make a cycle are only skipped there.
+    #[serde(default)]
+    pub parent: Parent<Uuid>,
+
+    /// Fields added by newer versions, kept so we store them again.
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+/// Tasks used to store completion in a last-writer-wins register. We read
+/// those as a flag set at the register's clock.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredComplete {
+    Flag(EWFlag),
+    Register(LWWRegister<bool>),
+}
+
+fn deserialize_complete<'de, D: Deserializer<'de>>(deserializer: D) -> Result<EWFlag, D::Error> {
+    Ok(match StoredComplete::deserialize(deserializer)? {
+        StoredComplete::Flag(flag) => flag,
+        StoredComplete::Register(register) => EWFlag::new(*register.value(), *register.clock()),
+    })
+}
+
+/// Descriptions used to be last-writer-wins registers. We read those as a
+/// register holding just that description, set at the same clock.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDescription {
+    Values(MVRegister<String>),
+    Register(LWWRegister<String>),
+}
+
+fn deserialize_description<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<MVRegister<String>, D::Error> {
+    Ok(match StoredDescription::deserialize(deserializer)? {
+        StoredDescription::Values(register) => register,
+        StoredDescription::Register(register) => {
+            MVRegister::new(register.value().clone(), *register.clock())
+        }
+    })
 }
 
 impl Task {
     #[tracing::instrument(name = "Task::new", skip(when))]
     pub fn new(description: String, when: HybridLogicalClock) -> Self {
         Self {
-            added: LWWRegister::new(Utc::now(), when),
-            complete: LWWRegister::new(false, when),
-            description: LWWRegister::new(description, when),
+            added: LWWRegister::new(when.timestamp(), when),
+            complete: EWFlag::new(false, when),
+            description: MVRegister::new(description, when),
+            text: None,
+            parent: Parent::default(),
+            unknown: UnknownFields::default(),
+        }
+    }
+
+    fn text_is_current(&self) -> bool {
+        self.text.as_ref().and_then(Text::max_clock) > self.description.max_clock()
+    }
+
+    pub fn description(&self) -> String {
+        match &self.text {
+            Some(text) if self.text_is_current() => text.to_string(),
+            _ => self.description.value().clone(),
+        }
+    }
+
+    /// Change the description by inserting and removing characters, so edits
+    /// made concurrently to other parts of it are kept.
+    #[tracing::instrument(name = "Task::edit_description", skip(self, clock))]
+    pub fn edit_description(&mut self, description: &str, clock: HybridLogicalClock) {
+        if !self.text_is_current() {
+            // Start from the description that's shown, typed in at the clock
+            // it was set at. Another replica doing the same thing ends up
+            // with identical characters.
+            let since = self
+                .description
+                .max_clock()
+                .expect("an MVRegister always has a clock");
+
+            let text = self.text.get_or_insert_default();
+            text.edit("", clock);
+            text.merge_mut(Text::new(self.description.value(), since));
+        }
+
+        if let Some(text) = &mut self.text {
+            text.edit(description, clock);
+        }
+    }
+
+    /// Descriptions set concurrently on different replicas that nobody has
+    /// picked between yet. Empty if there is no conflict.
+    pub fn conflicting_descriptions(&self) -> Vec<&String> {
+        if self.description.is_conflicted() && !self.text_is_current() {
+            self.description.values().collect()
+        } else {
+            Vec::new()
         }
     }
 }
 
-impl Merge for Task {
-    #[tracing::instrument(name = "Task::merge_mut", skip(self, other))]
-    fn merge_mut(&mut self, other: Self) {
-        self.added.merge_mut(other.added);
-        self.complete.merge_mut(other.complete);
-        self.description.merge_mut(other.description);
+impl Task {
+    /// Like `each_clock`, but also names the field each clock belongs to.
+    pub fn each_field_clock(&self, f: &mut dyn FnMut(&str, &HybridLogicalClock)) {
+        self.added.each_clock(&mut |clock| f("added", clock));
+        self.complete.each_clock(&mut |clock| f("complete", clock));
+        self.description
+            .each_clock(&mut |clock| f("description", clock));
+        if let Some(text) = &self.text {
+            text.each_clock(&mut |clock| f("text", clock));
+        }
+        self.parent.each_clock(&mut |clock| f("parent", clock));
+        for (field, value) in self.unknown.iter() {
+            value.each_clock(&mut |clock| f(field, clock));
+        }
+    }
+}
+
+impl Clocked for Task {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        self.each_field_clock(&mut |_, clock| f(clock));
+    }
+}
+
+impl Delta for Task {
+    /// Only the fields with something new, if there are any. A `Task` always
+    /// has a description, so an unchanged one comes along as just its current
+    /// values, and `added` is small enough to always send.
+    fn delta_since(&self, seen: &VersionVector) -> Option<Self> {
+        if seen.covers(self) {
+            return None;
+        }
+
+        Some(Task {
+            added: self.added.clone(),
+            complete: self.complete.delta_since(seen).unwrap_or_default(),
+            description: self
+                .description
+                .delta_since(seen)
+                .unwrap_or_else(|| self.description.without_context()),
+            text: self.text.as_ref().and_then(|text| text.delta_since(seen)),
+            parent: self.parent.delta_since(seen).unwrap_or_default(),
+            unknown: self
+                .unknown
+                .delta_since(seen)
+                .unwrap_or_else(|| self.unknown.clockless()),
+        })
     }
 }
 
 impl fmt::Display for Task {
     #[tracing::instrument(name = "Task::fmt", skip(self, f))]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let status = if *self.complete.value() { "[x]" } else { "[ ]" };
-        write!(f, "{} {}", status, self.description.value())
+        let status = if self.complete.value() { "[x]" } else { "[ ]" };
+        write!(f, "{} {}", status, self.description())?;
+
+        let conflicts = self.conflicting_descriptions().len();
+        if conflicts > 0 {
+            write!(f, " ({conflicts} conflicting descriptions)")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdt::hlc::WallClock;
+
+    #[test]
+    fn deltas_only_carry_what_changed() {
+        let mut clock = HybridLogicalClock::new(Uuid::nil(), &WallClock);
+        let mut task = Task::new("Walk dog".into(), clock);
+        clock.tick(&WallClock);
+        task.edit_description("Walk the dog", clock);
+
+        let mut seen = VersionVector::default();
+        seen.observe(clock);
+        clock.tick(&WallClock);
+        task.complete.set(true, clock);
+
+        let delta = task.delta_since(&seen).unwrap();
+        assert!(delta.text.is_none());
+        assert!(delta.complete.value());
+        assert_eq!(delta.description(), "Walk dog");
+
+        let merged = task.clone().merge(delta);
+        assert_eq!(merged.description(), "Walk the dog");
+    }
+
+    #[test]
+    fn reads_completion_stored_as_a_register() {
+        let clock = HybridLogicalClock::new(Uuid::nil(), &WallClock);
+
+        let mut stored = serde_json::to_value(Task::new("Walk dog".into(), clock)).unwrap();
+        stored["complete"] = serde_json::to_value(LWWRegister::new(true, clock)).unwrap();
+
+        let task: Task = serde_json::from_value(stored).unwrap();
+        assert!(task.complete.value());
+    }
+
+    #[test]
+    fn reads_descriptions_stored_as_a_register() {
+        let clock = HybridLogicalClock::new(Uuid::nil(), &WallClock);
+
+        let mut stored = serde_json::to_value(Task::new("Walk dog".into(), clock)).unwrap();
+        stored["description"] =
+            serde_json::to_value(LWWRegister::new("Feed cat".to_string(), clock)).unwrap();
+
+        let task: Task = serde_json::from_value(stored).unwrap();
+        assert_eq!(task.description(), "Feed cat");
+        assert_eq!(task.description.max_clock(), Some(clock));
     }
 }
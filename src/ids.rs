@@ -0,0 +1,74 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Where replicas and tasks get their IDs from.
+pub trait IdSource: Debug + Send + Sync {
+    fn next_id(&self) -> Uuid;
+}
+
+/// Random (v4) UUIDs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomIds;
+
+impl IdSource for RandomIds {
+    fn next_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// A reproducible stream of v4-shaped UUIDs, for tests and simulations.
+#[derive(Debug)]
+pub struct SeededIds(AtomicU64);
+
+impl SeededIds {
+    pub fn new(seed: u64) -> Self {
+        SeededIds(AtomicU64::new(seed))
+    }
+}
+
+impl IdSource for SeededIds {
+    fn next_id(&self) -> Uuid {
+        let high = splitmix64(&self.0);
+        let low = splitmix64(&self.0);
+
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&high.to_be_bytes());
+        bytes[8..].copy_from_slice(&low.to_be_bytes());
+
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+/// See https://prng.di.unimi.it/splitmix64.c
+fn splitmix64(state: &AtomicU64) -> u64 {
+    let mut z = state
+        .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seeded_ids_are_reproducible() {
+        let a = SeededIds::new(1);
+        let b = SeededIds::new(1);
+
+        for _ in 0..10 {
+            assert_eq!(a.next_id(), b.next_id());
+        }
+    }
+
+    #[test]
+    fn seeded_ids_are_v4() {
+        let ids = SeededIds::new(0);
+
+        assert_eq!(ids.next_id().get_version_num(), 4);
+    }
+}
@@ -0,0 +1,8 @@
+// Lets `#[derive(Merge)]` refer to `::rust_crdt_talk` from inside this crate.
+extern crate self as rust_crdt_talk;
+
+pub mod crdt;
+pub mod document;
+pub mod ids;
+pub mod replica;
+pub mod store;
@@ -1,10 +1,15 @@
-mod crdt;
-mod document;
-mod replica;
-
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use chrono::TimeDelta;
 use clap::{Parser, Subcommand};
-use replica::Replica;
+use rust_crdt_talk::document::Task;
+use rust_crdt_talk::replica::{self, Batch, Replica};
+use rust_crdt_talk::store::{
+    self, Backend, CURRENT_VERSION, FileStore, Format, Location, Options, Store, sibling,
+    write_file,
+};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
@@ -14,48 +19,94 @@ struct Cli {
     #[clap(subcommand)]
     command: Command,
 
-    /// Path to the database file
+    /// Path to the database file. Paths ending in `.sqlite`, `.sqlite3`, or
+    /// `.db` (or starting with `sqlite:`) are SQLite databases; anything else
+    /// (or a path starting with `file:`) is a single file. Start the path
+    /// with `journal:` to keep a snapshot file and append each change to a
+    /// journal next to it.
     #[clap(long, global = true, default_value = "tasks.json")]
     store_path: PathBuf,
+
+    /// Format to write files in: `json` or `cbor`. Files in either format
+    /// can always be read. Without this, files are rewritten in the format
+    /// they're already in, and new files are JSON.
+    #[clap(long, global = true)]
+    format: Option<Format>,
+
+    /// Rewrite a journal store's snapshot once its journal grows past this
+    /// many bytes
+    #[clap(long, global = true, default_value_t = store::DEFAULT_JOURNAL_LIMIT)]
+    journal_limit: u64,
+
+    /// Refuse to merge replicas with clocks more than this many seconds ahead
+    /// of ours
+    #[clap(long, global = true, default_value_t = replica::DEFAULT_MAX_DRIFT.num_seconds() as u32)]
+    max_drift: u32,
 }
 
 impl Cli {
     fn run(&self) -> Result<()> {
-        let mut replica = self.load_replica().context("could not load replica")?;
+        // Held until we're done, so another invocation can't load the store
+        // while we're changing it and then overwrite our changes.
+        let location = Location::new(&self.store_path);
+        let _lock = lock_store(&location.path)?;
+
+        if let Command::Convert { path } = &self.command
+            && canonical(&Location::new(path).path) == canonical(&location.path)
+        {
+            bail!(
+                "can't convert a store into itself; convert it to a new path and move that into place"
+            );
+        }
+
+        let options = Options {
+            format: self.format,
+            journal_limit: self.journal_limit,
+        };
+        let mut store = location.open(&options).context("could not open store")?;
+        let loaded = store.load().context("could not load replica")?;
+
+        if let Command::Migrate = self.command {
+            return migrate_store(&location, store.as_mut(), loaded);
+        }
+
+        if self.command.writes()
+            && let Some(version) = store.version()
+            && version < CURRENT_VERSION
+        {
+            bail!(
+                "refusing to overwrite `{}`, which is stored in version {version}; run `migrate` first (it keeps a backup)",
+                location.path.display()
+            );
+        }
+
+        let mut replica = loaded.unwrap_or_else(Replica::new);
+        replica.set_max_drift(
+            TimeDelta::try_seconds(self.max_drift.into()).context("--max-drift is too large")?,
+        );
 
         let changed = tracing_texray::examine(tracing::info_span!("run")).in_scope(|| {
             self.command
-                .run(&mut replica)
+                .run(&mut replica, &options)
                 .context("could not run command")
         })?;
 
         if changed {
-            self.store_replica(&replica)
-                .context("could not store replica")?;
+            store.update(&replica).context("could not store replica")?;
         }
 
         Ok(())
     }
-
-    fn load_replica(&self) -> Result<Replica> {
-        load_replica(&self.store_path, true)
-    }
-
-    fn store_replica(&self, replica: &Replica) -> Result<()> {
-        let file = std::fs::File::create(&self.store_path)
-            .with_context(|| format!("could not create `{}`", self.store_path.display()))?;
-
-        serde_json::to_writer_pretty(file, replica)
-            .with_context(|| format!("could not write JSON to `{}`", self.store_path.display()))?;
-
-        Ok(())
-    }
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
     /// List all tasks
-    List,
+    List {
+        /// Show subtasks indented under the tasks they belong to
+        #[clap(long)]
+        tree: bool,
+    },
 
     /// Add a new task
     Add {
@@ -63,36 +114,152 @@ enum Command {
         description: Vec<String>,
     },
 
+    /// Move a task to just before another one
+    Move {
+        /// UUID of the task to move
+        id: Uuid,
+        /// UUID of the task to put it in front of. Moves it to the end if not
+        /// given.
+        #[clap(long)]
+        before: Option<Uuid>,
+    },
+
+    /// Make a task a subtask of another one
+    Nest {
+        /// UUID of the task to nest
+        id: Uuid,
+        /// UUID of the task to put it under. Makes it a top-level task again
+        /// if not given.
+        #[clap(long)]
+        under: Option<Uuid>,
+    },
+
     /// Update the description of an existing task
     Update {
         /// UUID of the task to update
         id: Uuid,
+        /// Apply the change character by character, keeping edits made
+        /// to other parts of the description on other replicas
+        #[clap(long)]
+        diff: bool,
         /// New description of the task
         description: Vec<String>,
     },
 
-    /// Mark a task as complete or incomplete
+    /// Pick one of a task's conflicting descriptions
+    Resolve {
+        /// UUID of the task to resolve
+        id: Uuid,
+        /// Which description to keep, as numbered in `list`
+        choice: usize,
+    },
+
+    /// Mark a task as complete
     Complete {
         /// UUID of the task to update
         id: Uuid,
     },
 
+    /// Mark a completed task as not complete. If another replica completes
+    /// the task at the same time, completing wins.
+    Reopen {
+        /// UUID of the task to update
+        id: Uuid,
+    },
+
+    /// Complete a task if it's open, or reopen it if it's complete
+    Toggle {
+        /// UUID of the task to update
+        id: Uuid,
+    },
+
     /// Archive completed tasks
     Archive,
 
     /// Merge two replicas together
     Merge {
-        /// Path to the other data file
+        /// Path to the other data file (JSON or SQLite), or to changes or
+        /// operations from `export`
+        other: PathBuf,
+    },
+
+    /// Write the changes another replica hasn't seen yet to a file it can
+    /// `merge`
+    Export {
+        /// Path to write the changes to
+        path: PathBuf,
+        /// ID of the replica the changes are for
+        #[clap(long)]
+        peer: Uuid,
+        /// Write the operations the replica is missing instead of the changed
+        /// state, unless it's too far behind for that
+        #[clap(long)]
+        ops: bool,
+    },
+
+    /// Show the operations this replica has logged for its peers
+    Log,
+
+    /// Copy this replica into another store, in that store's backend and
+    /// `--format`
+    Convert {
+        /// Path to the store to write, like `--store-path`
+        path: PathBuf,
+    },
+
+    /// Print this replica's ID
+    Id,
+
+    /// Compare what this replica and another have seen
+    Status {
+        /// Path to the other data file (JSON or SQLite)
         other: PathBuf,
     },
+
+    /// Forget archived tasks that every known replica has already seen
+    Gc,
+
+    /// Upgrade the store to the current layout version, keeping a backup of
+    /// the old one
+    Migrate,
 }
 
 impl Command {
-    fn run(&self, replica: &mut Replica) -> Result<bool> {
+    /// Whether running the command can change the replica, so it has to be
+    /// stored again afterwards.
+    fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Self::List { .. }
+                | Self::Export { .. }
+                | Self::Log
+                | Self::Convert { .. }
+                | Self::Id
+                | Self::Status { .. }
+                | Self::Migrate
+        )
+    }
+
+    fn run(&self, replica: &mut Replica, options: &Options) -> Result<bool> {
         match self {
-            Self::List => {
-                for (id, task) in replica.tasks() {
-                    println!("{task} ({id})");
+            Self::List { tree } => {
+                let tasks: Vec<(&Uuid, &Task)> = replica.tasks().collect();
+
+                if *tree {
+                    let tree = replica.tree();
+                    let mut children: HashMap<Option<&Uuid>, Vec<(&Uuid, &Task)>> = HashMap::new();
+                    for (id, task) in tasks {
+                        children
+                            .entry(tree.parent(id))
+                            .or_default()
+                            .push((id, task));
+                    }
+
+                    print_tree(&children, None, 0);
+                } else {
+                    for (id, task) in tasks {
+                        print_task(id, task, 0);
+                    }
                 }
 
                 Ok(false)
@@ -107,8 +274,49 @@ impl Command {
                 Ok(true)
             }
 
-            Self::Update { id, description } => {
-                if replica.update_task_description(id, description.join(" ")) {
+            Self::Move { id, before } => {
+                if replica.move_task(id, before.as_ref()) {
+                    eprintln!("Moved task");
+
+                    Ok(true)
+                } else {
+                    eprintln!("Task not found");
+
+                    Ok(false)
+                }
+            }
+
+            Self::Nest { id, under } => {
+                if replica.task(id).is_none()
+                    || under.is_some_and(|under| replica.task(&under).is_none())
+                {
+                    eprintln!("Task not found");
+
+                    Ok(false)
+                } else if replica.nest_task(id, under.as_ref()) {
+                    eprintln!("Nested task");
+
+                    Ok(true)
+                } else {
+                    eprintln!("Can't nest a task under itself or one of its subtasks");
+
+                    Ok(false)
+                }
+            }
+
+            Self::Update {
+                id,
+                diff,
+                description,
+            } => {
+                let description = description.join(" ");
+                let updated = if *diff {
+                    replica.edit_task_description(id, &description)
+                } else {
+                    replica.update_task_description(id, description)
+                };
+
+                if updated {
                     eprintln!("Updated task");
 
                     Ok(true)
@@ -119,6 +327,30 @@ impl Command {
                 }
             }
 
+            Self::Resolve { id, choice } => {
+                let Some(task) = replica.task(id) else {
+                    eprintln!("Task not found");
+
+                    return Ok(false);
+                };
+
+                let conflicts = task.conflicting_descriptions();
+                let Some(description) = choice
+                    .checked_sub(1)
+                    .and_then(|index| conflicts.get(index))
+                    .map(|description| description.to_string())
+                else {
+                    eprintln!("Task has {} conflicting descriptions", conflicts.len());
+
+                    return Ok(false);
+                };
+
+                replica.update_task_description(id, description);
+                eprintln!("Resolved task");
+
+                Ok(true)
+            }
+
             Self::Complete { id } => {
                 if replica.complete_task(id) {
                     eprintln!("Updated task");
@@ -131,6 +363,36 @@ impl Command {
                 }
             }
 
+            Self::Reopen { id } => {
+                if replica.reopen_task(id) {
+                    eprintln!("Updated task");
+
+                    Ok(true)
+                } else {
+                    eprintln!("Task not found");
+
+                    Ok(false)
+                }
+            }
+
+            Self::Toggle { id } => match replica.toggle_task(id) {
+                Some(true) => {
+                    eprintln!("Completed task");
+
+                    Ok(true)
+                }
+                Some(false) => {
+                    eprintln!("Reopened task");
+
+                    Ok(true)
+                }
+                None => {
+                    eprintln!("Task not found");
+
+                    Ok(false)
+                }
+            },
+
             Self::Archive => {
                 replica.archive_completed_tasks();
 
@@ -140,31 +402,277 @@ impl Command {
             }
 
             Self::Merge { other } => {
+                match load_changes(other).context("could not load replica to merge")? {
+                    Changes::Replica(other_replica) => {
+                        replica
+                            .receive(*other_replica)
+                            .context("refusing to merge replicas")?;
+
+                        eprintln!("Merged replicas");
+                    }
+                    Changes::Operations(batch) => {
+                        let applied = replica
+                            .receive_operations(batch)
+                            .context("refusing to apply operations")?;
+
+                        eprintln!("Applied {applied} operations");
+
+                        let waiting = replica.waiting_operations();
+                        if waiting > 0 {
+                            eprintln!(
+                                "{waiting} operations are waiting for operations they depend on"
+                            );
+                        }
+                    }
+                }
+
+                Ok(true)
+            }
+
+            Self::Export { path, peer, ops } => {
+                if *ops {
+                    if let Some(batch) = replica.operations_for(peer) {
+                        write_file(path, options.format.unwrap_or_default(), &batch)
+                            .context("could not export operations")?;
+                        eprintln!("Exported {} operations", batch.operations.len());
+
+                        return Ok(false);
+                    }
+
+                    eprintln!("Peer is too far behind for operations, exporting changes instead");
+                }
+
+                let delta = replica.delta_for(peer);
+                FileStore::new(path, options.format)
+                    .save(&delta)
+                    .context("could not export changes")?;
+
+                eprintln!("Exported {} changed tasks", delta.tasks().count());
+
+                Ok(false)
+            }
+
+            Self::Log => {
+                for operation in replica.operations() {
+                    println!(
+                        "{} {}",
+                        operation.clock.timestamp().to_rfc3339(),
+                        operation.kind
+                    );
+                }
+
+                Ok(false)
+            }
+
+            Self::Convert { path } => {
+                let location = Location::new(path);
+                let _lock = lock_store(&location.path)?;
+
+                location
+                    .open(options)?
+                    .save(replica)
+                    .with_context(|| format!("could not write `{}`", location.path.display()))?;
+
+                eprintln!("Converted replica");
+
+                Ok(false)
+            }
+
+            Self::Id => {
+                println!("{}", replica.id());
+
+                Ok(false)
+            }
+
+            Self::Status { other } => {
                 let other_replica =
-                    load_replica(other, false).context("could not load replica to merge")?;
+                    load_replica(other).context("could not load replica to compare")?;
+
+                match replica.compare(&other_replica) {
+                    Some(Ordering::Equal) => println!("equal: both have seen the same changes"),
+                    Some(Ordering::Greater) => {
+                        println!(
+                            "dominates: `{}` has nothing we haven't seen",
+                            other.display()
+                        )
+                    }
+                    Some(Ordering::Less) => {
+                        println!(
+                            "dominated: `{}` has changes we haven't seen",
+                            other.display()
+                        )
+                    }
+                    None => println!("concurrent: each has changes the other hasn't seen"),
+                }
+
+                Ok(false)
+            }
 
-                replica.receive(other_replica);
+            Self::Gc => {
+                let collected = replica.gc().context("refusing to collect tombstones")?;
 
-                eprintln!("Merged replicas");
+                eprintln!("Collected {collected} tombstones");
 
-                Ok(true)
+                Ok(collected > 0)
             }
+
+            Self::Migrate => unreachable!("migrating needs the store, so `Cli::run` does it"),
         }
     }
 }
 
-fn load_replica(path: &Path, load_default: bool) -> Result<Replica> {
-    if load_default && !path.exists() {
-        return Ok(Replica::new());
+fn print_task(id: &Uuid, task: &Task, depth: usize) {
+    let indent = "    ".repeat(depth);
+    println!("{indent}{task} ({id})");
+
+    for (choice, description) in task.conflicting_descriptions().iter().enumerate() {
+        println!("{indent}    {}: {description}", choice + 1);
+    }
+}
+
+/// Print the tasks under `parent` (or the top-level tasks if it's `None`),
+/// each followed by its own subtasks. `children` lists each task's subtasks
+/// in order.
+fn print_tree(
+    children: &HashMap<Option<&Uuid>, Vec<(&Uuid, &Task)>>,
+    parent: Option<&Uuid>,
+    depth: usize,
+) {
+    for (id, task) in children.get(&parent).into_iter().flatten() {
+        print_task(id, task, depth);
+        print_tree(children, Some(id), depth + 1);
+    }
+}
+
+/// Load the replica stored at `location`, which has to exist.
+fn load_replica(location: &Path) -> Result<Replica> {
+    let location = Location::new(location);
+    if !location.path.exists() {
+        bail!("`{}` does not exist", location.path.display());
+    }
+
+    location
+        .open(&Options::default())?
+        .load()?
+        .with_context(|| format!("`{}` has no replica in it", location.path.display()))
+}
+
+/// What `merge` can read: a whole replica (or the changes from `export`), or
+/// operations from `export --ops`.
+enum Changes {
+    Replica(Box<Replica>),
+    Operations(Batch),
+}
+
+fn load_changes(path: &Path) -> Result<Changes> {
+    if Location::new(path).backend != Backend::File {
+        return Ok(Changes::Replica(Box::new(load_replica(path)?)));
+    }
+
+    /// Just enough of the file to tell what's in it.
+    #[derive(serde::Deserialize)]
+    struct Probe {
+        operations: Option<serde::de::IgnoredAny>,
+    }
+
+    let bytes =
+        std::fs::read(path).with_context(|| format!("could not read `{}`", path.display()))?;
+    let format = Format::detect(&bytes);
+    let read_error = || format!("could not read `{}` as {format}", path.display());
+
+    let probe: Probe = format.decode(&bytes).with_context(read_error)?;
+    let changes = if probe.operations.is_some() {
+        Changes::Operations(format.decode(&bytes).with_context(read_error)?)
+    } else {
+        let (replica, _) = store::decode_replica(&bytes).with_context(read_error)?;
+        Changes::Replica(Box::new(replica))
+    };
+
+    Ok(changes)
+}
+
+/// Rewrite the store at `location` in the current version, after copying its
+/// files to backups named for the version they were in.
+fn migrate_store(
+    location: &Location,
+    store: &mut dyn Store,
+    loaded: Option<Replica>,
+) -> Result<()> {
+    let (Some(replica), Some(version)) = (loaded, store.version()) else {
+        eprintln!("Nothing is stored at `{}` yet", location.path.display());
+        return Ok(());
+    };
+
+    if version == CURRENT_VERSION {
+        eprintln!("Already at version {version}");
+        return Ok(());
+    }
+
+    for file in location.files().iter().filter(|file| file.exists()) {
+        let backup = sibling(file, &format!("v{version}.bak"));
+        std::fs::copy(file, &backup).with_context(|| {
+            format!(
+                "could not back up `{}` to `{}`",
+                file.display(),
+                backup.display()
+            )
+        })?;
+
+        eprintln!("Backed up `{}` to `{}`", file.display(), backup.display());
     }
 
-    let file = std::fs::File::open(path)
-        .with_context(|| format!("could not open `{}`", path.display()))?;
+    store
+        .save(&replica)
+        .context("could not store migrated replica")?;
 
-    let replica: Replica = serde_json::from_reader(file)
-        .with_context(|| format!("could not read `{}` as JSON", path.display()))?;
+    eprintln!("Migrated from version {version} to version {CURRENT_VERSION}");
 
-    Ok(replica)
+    Ok(())
+}
+
+/// `path` made absolute, with symlinks and `.`/`..` resolved, so two paths to
+/// the same file compare equal. The file itself doesn't have to exist yet,
+/// but its directory does for this to work.
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    match (dir.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Take an advisory lock on the store at `path`, held until the returned
+/// file is dropped. The lock is on a separate `.lock` file, since renaming a
+/// new store into place would replace a lock held on the store itself.
+fn lock_store(path: &Path) -> Result<File> {
+    let lock_path = sibling(path, "lock");
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("could not open `{}`", lock_path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => bail!(
+            "another process is using `{}` (it holds a lock on `{}`)",
+            path.display(),
+            lock_path.display()
+        ),
+        Err(TryLockError::Error(err)) => {
+            Err(err).with_context(|| format!("could not lock `{}`", lock_path.display()))
+        }
+    }
 }
 
 fn main() {
@@ -1,67 +1,1095 @@
-use crate::crdt::{HybridLogicalClock, Merge};
+mod causal_buffer;
+mod operation;
+
+use crate::crdt::hlc::{Clock, WallClock};
+use crate::crdt::{Clocked, Delta, HybridLogicalClock, Merge, Tree, VersionVector};
 use crate::document::{Document, Task};
+use crate::ids::{IdSource, RandomIds};
+use chrono::TimeDelta;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+use causal_buffer::CausalBuffer;
+use operation::OperationLog;
+pub use operation::{Batch, Operation, OperationKind};
+
+/// How far ahead of our wall clock a received clock may be before we refuse
+/// to merge it.
+pub const DEFAULT_MAX_DRIFT: TimeDelta = TimeDelta::minutes(5);
+
+/// How many operations the log keeps for peers that haven't seen them. Peers
+/// further behind than this catch up with `delta_for` instead.
+const MAX_LOGGED_OPERATIONS: usize = 1000;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Replica {
     id: Uuid,
     clock: HybridLogicalClock,
+
+    /// Everything this replica has seen, including its own operations.
+    #[serde(default)]
+    seen: VersionVector,
+
+    /// What we last heard each other replica had seen.
+    #[serde(default)]
+    peers: BTreeMap<Uuid, VersionVector>,
+
     document: Document,
+
+    /// Changes we have made or received that some peer may not have seen
+    /// yet, as operations for peers that sync operation by operation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    log: Option<OperationLog>,
+
+    /// Operations received before the operations they depend on.
+    #[serde(default, skip_serializing_if = "CausalBuffer::is_empty")]
+    buffer: CausalBuffer,
+
+    /// Operations made here since the replica was loaded, or `None` if it
+    /// has also changed some other way since then.
+    #[serde(skip, default = "default_unsaved")]
+    unsaved: Option<Vec<Operation>>,
+
+    #[serde(skip, default = "default_max_drift")]
+    max_drift: TimeDelta,
+
+    #[serde(skip, default = "default_time")]
+    time: Arc<dyn Clock>,
+
+    #[serde(skip, default = "default_ids")]
+    ids: Arc<dyn IdSource>,
+}
+
+fn default_max_drift() -> TimeDelta {
+    DEFAULT_MAX_DRIFT
+}
+
+fn default_time() -> Arc<dyn Clock> {
+    Arc::new(WallClock)
+}
+
+fn default_ids() -> Arc<dyn IdSource> {
+    Arc::new(RandomIds)
+}
+
+fn default_unsaved() -> Option<Vec<Operation>> {
+    Some(Vec::new())
+}
+
+impl Default for Replica {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Replica {
     #[tracing::instrument(name = "Replica::new")]
     pub fn new() -> Self {
-        let id = Uuid::new_v4();
-        let clock = HybridLogicalClock::new(id);
+        Self::with_sources(default_time(), default_ids())
+    }
+
+    /// Make a replica that gets its time and IDs from somewhere other than
+    /// the wall clock and random UUIDs, so tests and simulations can be
+    /// reproduced exactly.
+    #[tracing::instrument(name = "Replica::with_sources")]
+    pub fn with_sources(time: Arc<dyn Clock>, ids: Arc<dyn IdSource>) -> Self {
+        let id = ids.next_id();
+        let clock = HybridLogicalClock::new(id, &*time);
 
         Self {
             id,
             clock,
+            seen: VersionVector::default(),
+            peers: BTreeMap::default(),
             document: Document::default(),
+            log: None,
+            buffer: CausalBuffer::default(),
+            unsaved: default_unsaved(),
+            max_drift: DEFAULT_MAX_DRIFT,
+            time,
+            ids,
         }
     }
 
+    /// Swap the time and ID sources of a replica, for example after loading
+    /// it from disk.
+    pub fn set_sources(&mut self, time: Arc<dyn Clock>, ids: Arc<dyn IdSource>) {
+        self.time = time;
+        self.ids = ids;
+    }
+
+    pub fn set_max_drift(&mut self, max_drift: TimeDelta) {
+        self.max_drift = max_drift;
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
     #[tracing::instrument(name = "Replica::tasks", skip(self))]
     pub fn tasks(&self) -> impl Iterator<Item = (&Uuid, &Task)> {
         self.document.tasks()
     }
 
+    #[tracing::instrument(name = "Replica::tree", skip(self))]
+    pub fn tree(&self) -> Tree<Uuid> {
+        self.document.tree()
+    }
+
+    #[tracing::instrument(name = "Replica::task", skip(self))]
+    pub fn task(&self, id: &Uuid) -> Option<&Task> {
+        self.document.task(id)
+    }
+
     #[tracing::instrument(name = "Replica::add_task", skip(self))]
     pub fn add_task(&mut self, description: String) -> Uuid {
-        let clock = self.next_clock();
+        let ids = self.ids.clone();
+        let logged = description.clone();
 
-        self.document.add_task(description, clock)
+        self.change(
+            |document, clock| document.add_task(description, clock, &*ids),
+            |id| OperationKind::AddTask {
+                id: *id,
+                description: logged,
+            },
+        )
     }
 
     #[tracing::instrument(name = "Replica::next_clock", skip(self))]
     fn next_clock(&mut self) -> HybridLogicalClock {
-        self.clock.tick();
+        self.clock.tick(&*self.time);
 
         self.clock
     }
 
+    /// Make a local change to the document at a fresh clock. If it changed
+    /// anything, log the resulting delta as the operation `describe`
+    /// returns. Changes that do nothing (like updating
+    /// a task that doesn't exist) don't count as seen, so no peer ends up
+    /// waiting for them.
+    fn change<T>(
+        &mut self,
+        change: impl FnOnce(&mut Document, HybridLogicalClock) -> T,
+        describe: impl FnOnce(&T) -> OperationKind,
+    ) -> T {
+        let before = self.seen.clone();
+        let clock = self.next_clock();
+        let result = change(&mut self.document, clock);
+
+        let log = self.log.get_or_insert_with(|| OperationLog {
+            since: before.clone(),
+            operations: Vec::new(),
+        });
+        if let Some(delta) = self.document.delta_since(&before) {
+            let operation = Operation {
+                clock,
+                kind: describe(&result),
+                deps: before,
+                changes: delta,
+            };
+
+            self.seen.observe(clock);
+            if let Some(unsaved) = &mut self.unsaved {
+                unsaved.push(operation.clone());
+            }
+            log.operations.push(operation);
+            self.prune_log();
+        }
+
+        result
+    }
+
+    #[tracing::instrument(name = "Replica::move_task", skip(self))]
+    pub fn move_task(&mut self, id: &Uuid, before: Option<&Uuid>) -> bool {
+        self.change(
+            |document, clock| document.move_task(id, before, clock),
+            |_| OperationKind::MoveTask {
+                id: *id,
+                before: before.copied(),
+            },
+        )
+    }
+
+    #[tracing::instrument(name = "Replica::nest_task", skip(self))]
+    pub fn nest_task(&mut self, id: &Uuid, parent: Option<&Uuid>) -> bool {
+        self.change(
+            |document, clock| document.nest_task(id, parent, clock),
+            |_| OperationKind::NestTask {
+                id: *id,
+                parent: parent.copied(),
+            },
+        )
+    }
+
     #[tracing::instrument(name = "Replica::update_task_description", skip(self))]
     pub fn update_task_description(&mut self, id: &Uuid, description: String) -> bool {
-        let clock = self.next_clock();
+        let logged = description.clone();
 
-        self.document
-            .update_task_description(id, description, clock)
+        self.change(
+            |document, clock| document.update_task_description(id, description, clock),
+            |_| OperationKind::UpdateTaskDescription {
+                id: *id,
+                description: logged,
+            },
+        )
+    }
+
+    #[tracing::instrument(name = "Replica::edit_task_description", skip(self))]
+    pub fn edit_task_description(&mut self, id: &Uuid, description: &str) -> bool {
+        self.change(
+            |document, clock| document.edit_task_description(id, description, clock),
+            |_| OperationKind::EditTaskDescription {
+                id: *id,
+                description: description.to_string(),
+            },
+        )
     }
 
     #[tracing::instrument(name = "Replica::complete_task", skip(self))]
     pub fn complete_task(&mut self, id: &Uuid) -> bool {
-        let clock = self.next_clock();
+        self.change(
+            |document, clock| document.set_task_complete(id, true, clock),
+            |_| OperationKind::SetTaskComplete {
+                id: *id,
+                complete: true,
+            },
+        )
+    }
 
-        self.document.complete_task(id, clock)
+    #[tracing::instrument(name = "Replica::reopen_task", skip(self))]
+    pub fn reopen_task(&mut self, id: &Uuid) -> bool {
+        self.change(
+            |document, clock| document.set_task_complete(id, false, clock),
+            |_| OperationKind::SetTaskComplete {
+                id: *id,
+                complete: false,
+            },
+        )
+    }
+
+    #[tracing::instrument(name = "Replica::toggle_task", skip(self))]
+    pub fn toggle_task(&mut self, id: &Uuid) -> Option<bool> {
+        self.change(
+            |document, clock| document.toggle_task(id, clock),
+            |complete| OperationKind::SetTaskComplete {
+                id: *id,
+                complete: *complete == Some(true),
+            },
+        )
     }
 
     pub fn archive_completed_tasks(&mut self) {
-        self.document.archive_completed_tasks()
+        self.change(
+            |document, clock| document.archive_completed_tasks(clock),
+            |_| OperationKind::ArchiveCompletedTasks,
+        )
+    }
+
+    /// Operations this replica has logged, oldest first.
+    pub fn operations(&self) -> impl Iterator<Item = &Operation> {
+        self.log.iter().flat_map(|log| &log.operations)
     }
 
-    pub fn receive(&mut self, other: Replica) {
+    /// The operations made here since this replica was loaded, oldest first.
+    /// `None` if it has also changed in ways operations don't capture (like
+    /// merging or collecting garbage), so only storing the whole replica will
+    /// do.
+    pub fn unsaved_operations(&self) -> Option<&[Operation]> {
+        self.unsaved.as_deref()
+    }
+
+    /// Make an operation from `unsaved_operations` again on a copy of this
+    /// replica loaded from before it was made, leaving the copy just like
+    /// this replica was after making it. Does nothing if the copy already
+    /// has the operation.
+    #[tracing::instrument(name = "Replica::replay", skip(self, operation))]
+    pub fn replay(&mut self, operation: Operation) {
+        if self.seen.contains(&operation.clock) {
+            return;
+        }
+
+        self.clock = self.clock.max(operation.clock);
+        self.seen.observe(operation.clock);
+        self.document.merge_mut(operation.changes.clone());
+
+        self.log
+            .get_or_insert_with(|| OperationLog {
+                since: operation.deps.clone(),
+                operations: Vec::new(),
+            })
+            .operations
+            .push(operation);
+        self.prune_log();
+    }
+
+    /// Merge another replica into this one. Refuses (leaving this replica
+    /// untouched) if any of the other replica's clocks are further ahead of
+    /// our wall clock than `max_drift`.
+    pub fn receive(&mut self, other: Replica) -> Result<(), ClockDrift> {
+        other.check_drift(self.max_drift, &*self.time)?;
+        self.unsaved = None;
+
+        if let Some(latest) = other.max_clock() {
+            self.clock.observe(&latest, &*self.time);
+        }
+
+        // The log can't describe changes that arrived as state, so from now
+        // on it only has what's new since this merge.
+        if let Some(log) = &mut self.log
+            && !self.seen.dominates(&other.seen)
+        {
+            let mut since = self.seen.clone();
+            since.merge_mut(other.seen.clone());
+
+            log.since = since;
+            log.operations.clear();
+        }
+
         self.document.merge_mut(other.document);
-        self.clock = self.clock.max(other.clock).claim(self.id);
+
+        for (peer, seen) in other.peers {
+            if peer != self.id {
+                self.peers.entry(peer).or_default().merge_mut(seen);
+            }
+        }
+
+        self.peers
+            .entry(other.id)
+            .or_default()
+            .merge_mut(other.seen.clone());
+        self.seen.merge_mut(other.seen);
+        self.deliver_buffered();
+        self.prune_log();
+
+        Ok(())
+    }
+
+    /// Apply operations from another replica, skipping any we've already
+    /// seen. Operations that depend on something we haven't seen yet wait
+    /// until it arrives. Returns how many were applied (including ones that
+    /// were waiting), or refuses (leaving this replica untouched) if any of
+    /// their clocks are further ahead of our wall clock than `max_drift`.
+    #[tracing::instrument(name = "Replica::receive_operations", skip(self, batch))]
+    pub fn receive_operations(&mut self, batch: Batch) -> Result<usize, ClockDrift> {
+        check_drift(batch.replica, self.max_drift, &*self.time, |check| {
+            batch
+                .seen
+                .each_clock(&mut |clock| check(format!("seen.{}", clock.node_id()), clock));
+            for (index, operation) in batch.operations.iter().enumerate() {
+                check(format!("operations.{index}.clock"), &operation.clock);
+                operation.deps.each_clock(&mut |clock| {
+                    check(
+                        format!("operations.{index}.deps.{}", clock.node_id()),
+                        clock,
+                    )
+                });
+                operation.changes.each_located_clock(&mut |field, clock| {
+                    check(format!("operations.{index}.changes.{field}"), clock)
+                });
+            }
+        })?;
+        self.unsaved = None;
+
+        for operation in batch.operations {
+            if let Some(latest) = operation.max_clock() {
+                self.clock.observe(&latest, &*self.time);
+            }
+
+            if !self.seen.contains(&operation.clock) {
+                self.buffer.push(operation);
+            }
+        }
+        let applied = self.deliver_buffered();
+
+        if batch.replica != self.id {
+            self.peers
+                .entry(batch.replica)
+                .or_default()
+                .merge_mut(batch.seen);
+        }
+        self.prune_log();
+
+        Ok(applied)
+    }
+
+    /// Apply every buffered operation whose dependencies we've now seen.
+    /// Returns how many were applied.
+    #[tracing::instrument(name = "Replica::deliver_buffered", skip(self))]
+    fn deliver_buffered(&mut self) -> usize {
+        let mut applied = 0;
+        while let Some(operation) = self.buffer.pop_ready(&self.seen) {
+            self.document.merge_mut(operation.changes.clone());
+            self.seen.observe(operation.clock);
+
+            if let Some(log) = &mut self.log {
+                log.operations.push(operation);
+            }
+            applied += 1;
+        }
+
+        applied
+    }
+
+    /// How many received operations are waiting for operations they depend
+    /// on.
+    pub fn waiting_operations(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The operations `peer` hasn't seen, or `None` if some of them are no
+    /// longer in the log and it needs `delta_for` instead.
+    #[tracing::instrument(name = "Replica::operations_for", skip(self))]
+    pub fn operations_for(&self, peer: &Uuid) -> Option<Batch> {
+        let seen = self.peers.get(peer).cloned().unwrap_or_default();
+        let log = self.log.as_ref().filter(|log| seen.dominates(&log.since))?;
+
+        Some(Batch {
+            replica: self.id,
+            seen: self.seen.clone(),
+            operations: log
+                .operations
+                .iter()
+                .filter(|operation| !seen.contains(&operation.clock))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Drop operations every known replica has seen from the log, and the
+    /// oldest ones once there are more than `MAX_LOGGED_OPERATIONS`. With no
+    /// known peers that's all of them.
+    #[tracing::instrument(name = "Replica::prune_log", skip(self))]
+    fn prune_log(&mut self) {
+        let stable = self.stable();
+
+        if let Some(log) = &mut self.log {
+            log.operations
+                .retain(|operation| !stable.contains(&operation.clock));
+            log.since.merge_mut(stable);
+
+            let excess = log.operations.len().saturating_sub(MAX_LOGGED_OPERATIONS);
+            for operation in log.operations.drain(..excess) {
+                log.since.observe(operation.clock);
+            }
+        }
+    }
+
+    /// What `peer` needs to catch up with us: this replica, but with only the
+    /// parts of the document it hasn't seen. `receive` accepts it just like a
+    /// full replica. If we've never heard from `peer`, that's everything.
+    #[tracing::instrument(name = "Replica::delta_for", skip(self))]
+    pub fn delta_for(&self, peer: &Uuid) -> Replica {
+        let seen = self.peers.get(peer).cloned().unwrap_or_default();
+        let document = self.document.delta_since(&seen);
+
+        Replica {
+            id: self.id,
+            clock: self.clock,
+            seen: self.seen.clone(),
+            peers: self.peers.clone(),
+            document: document.unwrap_or_default(),
+            log: None,
+            buffer: CausalBuffer::default(),
+            unsaved: default_unsaved(),
+            max_drift: self.max_drift,
+            time: self.time.clone(),
+            ids: self.ids.clone(),
+        }
+    }
+
+    #[tracing::instrument(name = "Replica::check_drift", skip(self, time))]
+    fn check_drift(&self, max_drift: TimeDelta, time: &dyn Clock) -> Result<(), ClockDrift> {
+        check_drift(self.id, max_drift, time, |check| {
+            check("clock".into(), &self.clock);
+            self.seen
+                .each_clock(&mut |clock| check(format!("seen.{}", clock.node_id()), clock));
+            for (peer, seen) in &self.peers {
+                seen.each_clock(&mut |clock| {
+                    check(format!("peers.{peer}.{}", clock.node_id()), clock)
+                });
+            }
+            self.document.each_located_clock(check);
+        })
+    }
+
+    /// How what this replica has seen compares to `other`: `Greater` if we
+    /// have everything it has and more, `Less` if it is ahead of us, and
+    /// `None` if each has changes the other hasn't seen.
+    pub fn compare(&self, other: &Replica) -> Option<Ordering> {
+        self.seen.partial_cmp(&other.seen)
+    }
+
+    /// The events every known replica (including this one) has seen.
+    #[tracing::instrument(name = "Replica::stable", skip(self))]
+    pub fn stable(&self) -> VersionVector {
+        self.peers
+            .values()
+            .fold(self.seen.clone(), |stable, seen| stable.meet(seen))
+    }
+
+    /// Drop tombstones for removes every known replica has seen. Returns the
+    /// number of tombstones collected, or refuses if any known replica has not
+    /// caught up with us yet.
+    #[tracing::instrument(name = "Replica::gc", skip(self))]
+    pub fn gc(&mut self) -> Result<usize, PeersBehind> {
+        let behind: Vec<Uuid> = self
+            .peers
+            .iter()
+            .filter(|(_, seen)| !seen.dominates(&self.seen))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        if !behind.is_empty() {
+            return Err(PeersBehind(behind));
+        }
+
+        let stable = self.stable();
+        self.unsaved = None;
+
+        Ok(self.document.gc(&stable))
+    }
+}
+
+impl Clocked for Replica {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        f(&self.clock);
+        self.seen.each_clock(f);
+        self.peers.values().for_each(|seen| seen.each_clock(f));
+        self.document.each_clock(f);
+    }
+}
+
+/// Refuse clocks further ahead of our wall clock than `max_drift`. `visit`
+/// calls the function it's given with every clock `replica` sent, along with
+/// where the clock came from.
+fn check_drift(
+    replica: Uuid,
+    max_drift: TimeDelta,
+    time: &dyn Clock,
+    visit: impl FnOnce(&mut dyn FnMut(String, &HybridLogicalClock)),
+) -> Result<(), ClockDrift> {
+    // A limit past the end of time lets everything through.
+    let limit = time.now().checked_add_signed(max_drift);
+    let mut fields = BTreeMap::new();
+    visit(&mut |field, clock| {
+        if limit.is_some_and(|limit| clock.timestamp() > limit) {
+            let latest = fields.entry(field).or_insert(*clock);
+            *latest = (*clock).max(*latest);
+        }
+    });
+
+    if fields.is_empty() {
+        Ok(())
+    } else {
+        Err(ClockDrift {
+            replica,
+            max_drift,
+            fields,
+        })
+    }
+}
+
+/// A replica sent us clocks too far in the future, probably because its
+/// system clock is wrong.
+#[derive(Debug)]
+pub struct ClockDrift {
+    pub replica: Uuid,
+    pub max_drift: TimeDelta,
+    pub fields: BTreeMap<String, HybridLogicalClock>,
+}
+
+impl fmt::Display for ClockDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replica {} has clocks more than {}s ahead of ours in",
+            self.replica,
+            self.max_drift.num_seconds()
+        )?;
+
+        for (field, clock) in &self.fields {
+            write!(f, "\n  {field} ({})", clock.timestamp())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ClockDrift {}
+
+/// Known replicas that have not seen everything we have.
+#[derive(Debug)]
+pub struct PeersBehind(pub Vec<Uuid>);
+
+impl fmt::Display for PeersBehind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "these replicas have not seen all of our changes yet:")?;
+        for peer in &self.0 {
+            write!(f, " {peer}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for PeersBehind {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdt::hlc::ManualClock;
+    use crate::ids::SeededIds;
+    use chrono::{DateTime, Utc};
+    use proptest::prelude::*;
+
+    fn replica(time: &ManualClock, seed: u64) -> Replica {
+        Replica::with_sources(Arc::new(time.clone()), Arc::new(SeededIds::new(seed)))
+    }
+
+    fn epoch() -> ManualClock {
+        ManualClock::new(DateTime::<Utc>::UNIX_EPOCH)
+    }
+
+    proptest! {
+        #[test]
+        fn clocks_after_receive_are_newer(skews: Vec<(i64, bool)>) {
+            let local_time = epoch();
+            let other_time = epoch();
+            let mut local = replica(&local_time, 0);
+            let mut other = replica(&other_time, 1);
+
+            // The other replica's clock runs up to a minute ahead of ours,
+            // sometimes standing still so its counter has to do the work.
+            for (ahead, stand_still) in skews {
+                if !stand_still {
+                    local_time.advance(TimeDelta::seconds(1));
+                    other_time.set(
+                        local_time.now() + TimeDelta::milliseconds(ahead.rem_euclid(60_000)),
+                    );
+                }
+
+                let id = other.add_task("Walk dog".into());
+                other.update_task_description(&id, "Walk the dog".into());
+            }
+
+            let received = other.clone();
+            local.receive(other).unwrap();
+            let next = local.next_clock();
+
+            received.each_clock(&mut |clock| assert!(next > *clock, "{next:?} <= {clock:?}"));
+        }
+    }
+
+    #[test]
+    fn gc_collects_archived_tasks_once_peers_catch_up() {
+        let mut phone = Replica::new();
+        let mut computer = Replica::new();
+
+        phone.add_task("Buy milk".into());
+        let done = phone.add_task("Walk dog".into());
+        phone.complete_task(&done);
+        phone.archive_completed_tasks();
+
+        computer.receive(phone.clone()).unwrap();
+        phone.receive(computer).unwrap();
+
+        // The archived task and its place in the order.
+        assert_eq!(phone.gc().unwrap(), 2);
+        assert_eq!(phone.tasks().count(), 1);
+    }
+
+    #[test]
+    fn gc_refuses_when_a_peer_is_behind() {
+        let mut phone = Replica::new();
+        let computer = Replica::new();
+        let computer_id = computer.id;
+
+        phone.receive(computer).unwrap();
+
+        let done = phone.add_task("Walk dog".into());
+        phone.complete_task(&done);
+        phone.archive_completed_tasks();
+
+        assert_eq!(phone.gc().unwrap_err().0, vec![computer_id]);
+    }
+
+    #[test]
+    fn receive_refuses_clocks_from_the_future() {
+        let phone_time = epoch();
+        let computer_time = epoch();
+        let mut phone = replica(&phone_time, 0);
+        let mut computer = replica(&computer_time, 1);
+
+        let id = computer.add_task("Walk dog".into());
+        computer_time.advance(TimeDelta::days(365 * 5));
+        computer.update_task_description(&id, "Walk the dog".into());
+
+        let drift = phone.receive(computer).unwrap_err();
+        let fields: Vec<&str> = drift.fields.keys().map(String::as_str).collect();
+
+        assert!(fields.contains(&"clock"));
+        assert!(fields.contains(&format!("tasks.{id}.description").as_str()));
+        assert!(!fields.contains(&format!("tasks.{id}.added").as_str()));
+        assert_eq!(phone.tasks().count(), 0);
+    }
+
+    #[test]
+    fn the_largest_max_drift_lets_everything_through() {
+        let phone_time = epoch();
+        let computer_time = epoch();
+        let mut phone = replica(&phone_time, 0);
+        let mut computer = replica(&computer_time, 1);
+
+        computer_time.advance(TimeDelta::days(365 * 5));
+        computer.add_task("Walk dog".into());
+
+        phone.set_max_drift(TimeDelta::MAX);
+        phone.receive(computer).unwrap();
+        assert_eq!(phone.tasks().count(), 1);
+    }
+
+    #[test]
+    fn replicas_with_the_same_sources_are_identical() {
+        let run = || {
+            let time = epoch();
+            let mut phone = replica(&time, 0);
+            let mut computer = replica(&time, 1);
+
+            let id = phone.add_task("Walk dog".into());
+            time.advance(TimeDelta::seconds(1));
+            computer.receive(phone.clone()).unwrap();
+            computer.complete_task(&id);
+            phone.receive(computer).unwrap();
+
+            serde_json::to_string(&phone).unwrap()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn delta_for_a_peer_only_has_what_it_missed() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        let id = phone.add_task("Walk dog".into());
+        phone.add_task("Buy milk".into());
+        computer.receive(phone.delta_for(&computer.id)).unwrap();
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+
+        time.advance(TimeDelta::seconds(1));
+        phone.update_task_description(&id, "Walk the dog".into());
+
+        let delta = phone.delta_for(&computer.id);
+        assert_eq!(
+            delta.tasks().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![id]
+        );
+
+        computer.receive(delta).unwrap();
+        assert_eq!(
+            serde_json::to_value(&computer.document).unwrap(),
+            serde_json::to_value(&phone.document).unwrap(),
+        );
+    }
+
+    #[test]
+    fn delta_for_a_peer_that_caught_up_is_empty() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+
+        phone.add_task("Walk dog".into());
+        assert_eq!(phone.delta_for(&computer.id).tasks().count(), 1);
+
+        computer.receive(phone.delta_for(&computer.id)).unwrap();
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+        assert_eq!(phone.delta_for(&computer.id).tasks().count(), 0);
+    }
+
+    #[test]
+    fn compare_follows_merges() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+        assert_eq!(phone.compare(&computer), Some(Ordering::Equal));
+
+        phone.add_task("Walk dog".into());
+        assert_eq!(phone.compare(&computer), Some(Ordering::Greater));
+
+        computer.add_task("Buy milk".into());
+        assert_eq!(phone.compare(&computer), None);
+
+        computer.receive(phone.clone()).unwrap();
+        assert_eq!(phone.compare(&computer), Some(Ordering::Less));
+
+        phone.receive(computer.clone()).unwrap();
+        assert_eq!(phone.compare(&computer), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn concurrent_complete_wins_over_reopen() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        let id = phone.add_task("Walk dog".into());
+        phone.complete_task(&id);
+        computer.receive(phone.clone()).unwrap();
+
+        time.advance(TimeDelta::seconds(1));
+        computer.reopen_task(&id);
+        assert!(!computer.task(&id).unwrap().complete.value());
+
+        // The phone reopens and completes again without hearing about the
+        // computer's reopen, and it should stay completed.
+        assert_eq!(phone.toggle_task(&id), Some(false));
+        assert_eq!(phone.toggle_task(&id), Some(true));
+
+        computer.receive(phone).unwrap();
+        assert!(computer.task(&id).unwrap().complete.value());
+    }
+
+    #[test]
+    fn moves_reorder_tasks() {
+        let time = epoch();
+        let mut replica = replica(&time, 0);
+
+        let a = replica.add_task("a".into());
+        let b = replica.add_task("b".into());
+        let c = replica.add_task("c".into());
+
+        assert!(replica.move_task(&c, Some(&a)));
+        assert!(replica.move_task(&a, None));
+
+        let order: Vec<Uuid> = replica.tasks().map(|(id, _)| *id).collect();
+        assert_eq!(order, vec![c, b, a]);
+    }
+
+    #[test]
+    fn concurrent_moves_converge() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        let a = phone.add_task("a".into());
+        let b = phone.add_task("b".into());
+        let c = phone.add_task("c".into());
+        computer.receive(phone.clone()).unwrap();
+
+        time.advance(TimeDelta::seconds(1));
+        phone.move_task(&c, Some(&a));
+        computer.move_task(&a, None);
+        computer.move_task(&c, Some(&b));
+
+        let mut merged_phone = phone.clone();
+        merged_phone.receive(computer.clone()).unwrap();
+        computer.receive(phone).unwrap();
+
+        let phone_order: Vec<Uuid> = merged_phone.tasks().map(|(id, _)| *id).collect();
+        let computer_order: Vec<Uuid> = computer.tasks().map(|(id, _)| *id).collect();
+        assert_eq!(phone_order, computer_order);
+        assert_eq!(phone_order.len(), 3);
+        assert!([a, b, c].iter().all(|id| phone_order.contains(id)));
+    }
+
+    #[test]
+    fn concurrent_description_edits_are_both_kept() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        let id = phone.add_task("Walk the dog".into());
+        computer.receive(phone.clone()).unwrap();
+
+        time.advance(TimeDelta::seconds(1));
+        phone.edit_task_description(&id, "Walk the big dog");
+        computer.edit_task_description(&id, "Walk the dog twice");
+
+        computer.receive(phone).unwrap();
+        assert_eq!(
+            computer.task(&id).unwrap().description(),
+            "Walk the big dog twice"
+        );
+
+        // Setting the description outright replaces the edited text.
+        time.advance(TimeDelta::seconds(1));
+        computer.update_task_description(&id, "Feed the cat".into());
+        assert_eq!(computer.task(&id).unwrap().description(), "Feed the cat");
+    }
+
+    #[test]
+    fn concurrent_nesting_never_makes_a_cycle() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        let epic = phone.add_task("Move house".into());
+        let task = phone.add_task("Pack boxes".into());
+        computer.receive(phone.clone()).unwrap();
+
+        time.advance(TimeDelta::seconds(1));
+        assert!(phone.nest_task(&task, Some(&epic)));
+        assert!(computer.nest_task(&epic, Some(&task)));
+        assert!(!phone.nest_task(&epic, Some(&task)));
+
+        let mut merged_phone = phone.clone();
+        merged_phone.receive(computer.clone()).unwrap();
+        computer.receive(phone).unwrap();
+
+        let tree = computer.tree();
+        assert_eq!(tree, merged_phone.tree());
+        assert!(tree.parent(&epic).is_none() || tree.parent(&task).is_none());
+    }
+
+    #[test]
+    fn operations_catch_a_peer_up() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+
+        let id = phone.add_task("Walk dog".into());
+        let batch = phone.operations_for(&computer.id()).unwrap();
+        assert_eq!(computer.receive_operations(batch).unwrap(), 1);
+
+        phone.complete_task(&id);
+        let batch = phone.operations_for(&computer.id()).unwrap();
+        assert_eq!(batch.operations.len(), 2);
+
+        // The add has already been applied, so only the completion is new.
+        assert_eq!(computer.receive_operations(batch).unwrap(), 1);
+        assert!(computer.task(&id).unwrap().complete.value());
+        assert_eq!(computer.compare(&phone), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn operations_are_only_logged_for_known_peers() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let computer = replica(&time, 1);
+
+        phone.add_task("Walk dog".into());
+        assert_eq!(phone.operations().count(), 0);
+        assert!(phone.operations_for(&computer.id()).is_none());
+
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+        phone.add_task("Buy milk".into());
+        assert_eq!(phone.operations().count(), 1);
+        assert!(phone.operations_for(&computer.id()).is_none());
+    }
+
+    #[test]
+    fn the_log_drops_its_oldest_operations_when_full() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let computer = replica(&time, 1);
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+
+        let id = phone.add_task("Walk dog".into());
+        for _ in 0..MAX_LOGGED_OPERATIONS {
+            phone.toggle_task(&id);
+        }
+
+        assert_eq!(phone.operations().count(), MAX_LOGGED_OPERATIONS);
+        assert!(phone.operations_for(&computer.id()).is_none());
+        assert_eq!(phone.delta_for(&computer.id()).tasks().count(), 1);
+    }
+
+    #[test]
+    fn operations_fall_back_to_state_after_a_state_merge() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+        let mut tablet = replica(&time, 2);
+
+        phone.add_task("Walk dog".into());
+        computer.receive(phone.clone()).unwrap();
+        phone.receive(computer.clone()).unwrap();
+
+        tablet.add_task("Feed cat".into());
+        phone.receive(tablet).unwrap();
+
+        // The tablet's task arrived as state, so the log can't replay it.
+        assert!(phone.operations_for(&computer.id()).is_none());
+        assert_eq!(phone.delta_for(&computer.id()).tasks().count(), 1);
+    }
+
+    #[test]
+    fn operations_wait_for_the_operations_they_depend_on() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+        phone.receive(computer.delta_for(&phone.id)).unwrap();
+
+        // Updating a task that doesn't exist yet does nothing, so nobody
+        // should wait for it.
+        assert!(!phone.update_task_description(&Uuid::nil(), "Nothing".into()));
+
+        let id = phone.add_task("Walk dog".into());
+        phone.update_task_description(&id, "Walk the dog".into());
+
+        let mut batch = phone.operations_for(&computer.id()).unwrap();
+        let update = batch.operations.pop().unwrap();
+        let add = batch.clone();
+        batch.operations = vec![update];
+
+        assert_eq!(computer.receive_operations(batch).unwrap(), 0);
+        assert!(computer.task(&id).is_none());
+
+        // The buffer is kept with the replica.
+        let mut computer: Replica =
+            serde_json::from_value(serde_json::to_value(&computer).unwrap()).unwrap();
+        computer.set_sources(Arc::new(time.clone()), Arc::new(SeededIds::new(1)));
+        assert_eq!(computer.waiting_operations(), 1);
+
+        assert_eq!(computer.receive_operations(add).unwrap(), 2);
+        assert_eq!(computer.waiting_operations(), 0);
+        assert_eq!(computer.task(&id).unwrap().description(), "Walk the dog");
+    }
+
+    #[test]
+    fn replaying_unsaved_operations_rebuilds_the_replica() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        phone.add_task("Buy milk".into());
+
+        let saved: Replica = serde_json::from_value(serde_json::to_value(&phone).unwrap()).unwrap();
+        let mut phone = saved.clone();
+
+        let id = phone.add_task("Walk dog".into());
+        phone.edit_task_description(&id, "Walk the dog");
+        phone.complete_task(&id);
+        phone.archive_completed_tasks();
+
+        let mut replayed = saved;
+        for operation in phone.unsaved_operations().unwrap() {
+            replayed.replay(operation.clone());
+            replayed.replay(operation.clone());
+        }
+
+        assert_eq!(
+            serde_json::to_value(&replayed).unwrap(),
+            serde_json::to_value(&phone).unwrap()
+        );
+    }
+
+    #[test]
+    fn merging_leaves_nothing_to_replay() {
+        let time = epoch();
+        let mut phone = replica(&time, 0);
+        let mut computer = replica(&time, 1);
+
+        phone.add_task("Walk dog".into());
+        assert_eq!(phone.unsaved_operations().unwrap().len(), 1);
+
+        computer.add_task("Feed cat".into());
+        phone.receive(computer).unwrap();
+        assert!(phone.unsaved_operations().is_none());
     }
 }
@@ -0,0 +1,50 @@
+use super::Operation;
+use crate::crdt::VersionVector;
+
+/// Operations that arrived before something they depend on. They wait here
+/// (and are stored with the replica) until everything in their `deps` has
+/// been seen, so operations are always applied in an order where each one's
+/// causes come first.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(super) struct CausalBuffer {
+    waiting: Vec<Operation>,
+}
+
+impl CausalBuffer {
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    #[tracing::instrument(name = "CausalBuffer::push", skip(self, operation))]
+    pub fn push(&mut self, operation: Operation) {
+        if !self
+            .waiting
+            .iter()
+            .any(|waiting| waiting.clock == operation.clock)
+        {
+            self.waiting.push(operation);
+        }
+    }
+
+    /// Take the oldest operation that can be applied after `seen`, dropping
+    /// any that `seen` already has. Call it again after applying the
+    /// operation, since that may release others.
+    #[tracing::instrument(name = "CausalBuffer::pop_ready", skip(self, seen))]
+    pub fn pop_ready(&mut self, seen: &VersionVector) -> Option<Operation> {
+        self.waiting
+            .retain(|operation| !seen.contains(&operation.clock));
+
+        let (index, _) = self
+            .waiting
+            .iter()
+            .enumerate()
+            .filter(|(_, operation)| seen.dominates(&operation.deps))
+            .min_by_key(|(_, operation)| operation.clock)?;
+
+        Some(self.waiting.swap_remove(index))
+    }
+}
@@ -0,0 +1,92 @@
+use crate::crdt::{Clocked, HybridLogicalClock, VersionVector};
+use crate::document::Document;
+use std::fmt;
+use uuid::Uuid;
+
+/// A change made by a call to one of `Replica`'s methods.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Operation {
+    /// When the change was made, and by which replica.
+    pub clock: HybridLogicalClock,
+
+    pub kind: OperationKind,
+
+    /// Everything the replica had seen before making the operation. Other
+    /// replicas apply it only once they've seen all of this too.
+    pub deps: VersionVector,
+
+    /// What the operation changed in the document. Applying an operation
+    /// merges this in, so it has the same effect on every replica no matter
+    /// what else that replica has seen.
+    pub changes: Document,
+}
+
+impl Clocked for Operation {
+    fn each_clock(&self, f: &mut dyn FnMut(&HybridLogicalClock)) {
+        f(&self.clock);
+        self.deps.each_clock(f);
+        self.changes.each_clock(f);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OperationKind {
+    AddTask { id: Uuid, description: String },
+    MoveTask { id: Uuid, before: Option<Uuid> },
+    NestTask { id: Uuid, parent: Option<Uuid> },
+    UpdateTaskDescription { id: Uuid, description: String },
+    EditTaskDescription { id: Uuid, description: String },
+    SetTaskComplete { id: Uuid, complete: bool },
+    ArchiveCompletedTasks,
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddTask { id, description } => write!(f, "add {id}: {description}"),
+            Self::MoveTask { id, before: None } => write!(f, "move {id} to the end"),
+            Self::MoveTask {
+                id,
+                before: Some(before),
+            } => write!(f, "move {id} before {before}"),
+            Self::NestTask { id, parent: None } => write!(f, "move {id} to the top level"),
+            Self::NestTask {
+                id,
+                parent: Some(parent),
+            } => write!(f, "nest {id} under {parent}"),
+            Self::UpdateTaskDescription { id, description } => {
+                write!(f, "update {id}: {description}")
+            }
+            Self::EditTaskDescription { id, description } => {
+                write!(f, "edit {id}: {description}")
+            }
+            Self::SetTaskComplete { id, complete: true } => write!(f, "complete {id}"),
+            Self::SetTaskComplete {
+                id,
+                complete: false,
+            } => write!(f, "reopen {id}"),
+            Self::ArchiveCompletedTasks => write!(f, "archive completed tasks"),
+        }
+    }
+}
+
+/// Operations one replica is sending another, oldest first.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Batch {
+    pub replica: Uuid,
+
+    /// Everything the sending replica had seen when it made the batch.
+    pub seen: VersionVector,
+
+    pub operations: Vec<Operation>,
+}
+
+/// Operations made or received here that some peer may not have seen yet.
+/// Every change that isn't covered by `since` is in `operations`, so a peer
+/// that has seen `since` can catch up from the log alone.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(super) struct OperationLog {
+    pub since: VersionVector,
+    pub operations: Vec<Operation>,
+}
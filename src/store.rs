@@ -0,0 +1,192 @@
+mod file;
+mod format;
+mod journal;
+mod migration;
+mod sqlite;
+
+use crate::replica::Replica;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+pub use file::{FileStore, decode_replica, write_file};
+pub use format::Format;
+pub use journal::JournalStore;
+pub use migration::{CURRENT_VERSION, MIGRATIONS, Migration};
+pub use sqlite::SqliteStore;
+
+/// Somewhere a replica is kept between runs.
+pub trait Store {
+    /// Read the stored replica, or `None` if nothing has been stored yet.
+    fn load(&mut self) -> Result<Option<Replica>>;
+
+    /// Replace whatever is stored with `replica`.
+    fn save(&mut self, replica: &Replica) -> Result<()>;
+
+    /// Store `replica`, which was loaded from or last saved to this store,
+    /// writing only what changed if the store can tell.
+    fn update(&mut self, replica: &Replica) -> Result<()> {
+        self.save(replica)
+    }
+
+    /// The layout version of the replica `load` read (see `CURRENT_VERSION`),
+    /// or of the one last saved. `None` if neither has happened.
+    fn version(&self) -> Option<u32>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    File,
+    Journal,
+    Sqlite,
+}
+
+/// How far the journal of a journal store can grow before the snapshot is
+/// rewritten, in bytes.
+pub const DEFAULT_JOURNAL_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// What to write file stores and journal snapshots in, or `None` to keep
+    /// whatever they're in already (JSON for new ones).
+    pub format: Option<Format>,
+    pub journal_limit: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            format: None,
+            journal_limit: DEFAULT_JOURNAL_LIMIT,
+        }
+    }
+}
+
+/// Where a store lives and which backend to use for it. Written as a path,
+/// optionally prefixed with `file:` (or `json:`, from before there were
+/// other formats), `journal:`, or `sqlite:`. Without a prefix, paths ending
+/// in `.sqlite`, `.sqlite3`, or `.db` are SQLite databases and everything
+/// else is a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub backend: Backend,
+    pub path: PathBuf,
+}
+
+impl Location {
+    pub fn new(location: &Path) -> Self {
+        let schemes = [
+            ("file:", Backend::File),
+            ("json:", Backend::File),
+            ("journal:", Backend::Journal),
+            ("sqlite:", Backend::Sqlite),
+        ];
+        if let Some(location) = location.to_str() {
+            for (scheme, backend) in schemes {
+                if let Some(path) = location.strip_prefix(scheme) {
+                    return Location {
+                        backend,
+                        path: path.into(),
+                    };
+                }
+            }
+        }
+
+        let backend = match location
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("sqlite" | "sqlite3" | "db") => Backend::Sqlite,
+            _ => Backend::File,
+        };
+
+        Location {
+            backend,
+            path: location.to_path_buf(),
+        }
+    }
+
+    pub fn open(&self, options: &Options) -> Result<Box<dyn Store>> {
+        Ok(match self.backend {
+            Backend::File => Box::new(FileStore::new(&self.path, options.format)),
+            Backend::Journal => Box::new(JournalStore::new(
+                &self.path,
+                options.format,
+                options.journal_limit,
+            )),
+            Backend::Sqlite => Box::new(SqliteStore::open(&self.path)?),
+        })
+    }
+
+    /// Every file the store keeps, whether or not it exists yet.
+    pub fn files(&self) -> Vec<PathBuf> {
+        match self.backend {
+            Backend::File | Backend::Sqlite => vec![self.path.clone()],
+            Backend::Journal => vec![self.path.clone(), sibling(&self.path, "journal")],
+        }
+    }
+}
+
+/// `path` with `.extension` added to the end of its file name.
+pub fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+
+    path.with_file_name(name)
+}
+
+/// A fresh directory for a test's files, removed along with everything in it
+/// once the test is done with it.
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("{prefix}-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+
+        TempDir(dir)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backend_comes_from_the_scheme_or_extension() {
+        let cases = [
+            ("tasks.json", Backend::File, "tasks.json"),
+            ("tasks", Backend::File, "tasks"),
+            ("tasks.sqlite", Backend::Sqlite, "tasks.sqlite"),
+            ("dir/tasks.db", Backend::Sqlite, "dir/tasks.db"),
+            ("sqlite:tasks", Backend::Sqlite, "tasks"),
+            ("json:tasks.db", Backend::File, "tasks.db"),
+            ("file:tasks.cbor", Backend::File, "tasks.cbor"),
+            ("journal:tasks.json", Backend::Journal, "tasks.json"),
+        ];
+
+        for (location, backend, path) in cases {
+            assert_eq!(
+                Location::new(Path::new(location)),
+                Location {
+                    backend,
+                    path: path.into()
+                },
+                "{location}"
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use super::migration::{CURRENT_VERSION, Envelope, migrate};
+use super::{Format, Store, sibling};
+use crate::replica::Replica;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// The whole replica in one file, rewritten on every save. Loading works
+/// whatever format and version the file is in; saving writes the current
+/// version in `format`, or in the format the file was loaded in if that's
+/// `None` (JSON for a new file).
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+    format: Option<Format>,
+    version: Option<u32>,
+}
+
+impl FileStore {
+    pub fn new(path: &Path, format: Option<Format>) -> Self {
+        FileStore {
+            path: path.to_path_buf(),
+            format,
+            version: None,
+        }
+    }
+
+    /// The format `save` writes, if it's known yet.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+}
+
+impl Store for FileStore {
+    #[tracing::instrument(name = "FileStore::load", skip(self))]
+    fn load(&mut self) -> Result<Option<Replica>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let bytes = std::fs::read(&self.path)
+            .with_context(|| format!("could not read `{}`", self.path.display()))?;
+        let (replica, version) = decode_replica(&bytes)
+            .with_context(|| format!("could not read `{}`", self.path.display()))?;
+        self.version = Some(version);
+        self.format.get_or_insert(Format::detect(&bytes));
+
+        Ok(Some(replica))
+    }
+
+    #[tracing::instrument(name = "FileStore::save", skip(self, replica))]
+    fn save(&mut self, replica: &Replica) -> Result<()> {
+        let envelope = Envelope {
+            version: CURRENT_VERSION,
+            replica,
+        };
+        write_file(&self.path, self.format.unwrap_or_default(), &envelope)?;
+        self.version = Some(CURRENT_VERSION);
+
+        Ok(())
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+/// Decode a replica saved by `FileStore` in any format and version, along
+/// with the version it was in. Older versions are migrated.
+pub fn decode_replica(bytes: &[u8]) -> Result<(Replica, u32)> {
+    #[derive(serde::Deserialize)]
+    struct Version {
+        version: Option<u32>,
+    }
+
+    let format = Format::detect(bytes);
+    let version = format.decode::<Version>(bytes)?.version.unwrap_or(0);
+
+    if version == CURRENT_VERSION {
+        let envelope: Envelope<Replica> = format.decode(bytes)?;
+
+        return Ok((envelope.replica, version));
+    }
+
+    let mut replica = format.decode_value(bytes)?;
+    if version > 0 {
+        replica = replica["replica"].take();
+    }
+    let replica = serde_json::from_value(migrate(replica, version)?)?;
+
+    Ok((replica, version))
+}
+
+/// Write `value` to a temporary file next to `path` and then rename it into
+/// place, so a crash partway through leaves the old file intact.
+pub fn write_file(path: &Path, format: Format, value: &impl serde::Serialize) -> Result<()> {
+    let temp = sibling(path, "tmp");
+
+    let file =
+        File::create(&temp).with_context(|| format!("could not create `{}`", temp.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    format
+        .encode(&mut writer, value)
+        .with_context(|| format!("could not write {format} to `{}`", temp.display()))?;
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("could not flush `{}`", temp.display()))?;
+
+    std::fs::rename(&temp, path).with_context(|| {
+        format!(
+            "could not move `{}` to `{}`",
+            temp.display(),
+            path.display()
+        )
+    })?;
+
+    // Make sure the rename itself survives a crash.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("could not flush `{}`", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::TempDir;
+
+    #[test]
+    fn replicas_from_before_versions_are_version_zero() {
+        let mut replica = Replica::new();
+        replica.add_task("walk dog".into());
+        let json = serde_json::to_value(&replica).unwrap();
+
+        for format in [Format::Json, Format::Cbor] {
+            let mut bytes = Vec::new();
+            format.encode(&mut bytes, &replica).unwrap();
+
+            let (decoded, version) = decode_replica(&bytes).unwrap();
+            assert_eq!(version, 0, "{format}");
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json, "{format}");
+        }
+    }
+
+    #[test]
+    fn replicas_are_saved_in_the_current_version() {
+        let replica = Replica::new();
+        let envelope = Envelope {
+            version: CURRENT_VERSION,
+            replica: &replica,
+        };
+
+        for format in [Format::Json, Format::Cbor] {
+            let mut bytes = Vec::new();
+            format.encode(&mut bytes, &envelope).unwrap();
+
+            let (_, version) = decode_replica(&bytes).unwrap();
+            assert_eq!(version, CURRENT_VERSION, "{format}");
+        }
+    }
+
+    #[test]
+    fn files_are_saved_in_the_format_they_were_loaded_in() {
+        let dir = TempDir::new("file");
+        let path = dir.join("tasks.json");
+
+        let mut replica = Replica::new();
+        FileStore::new(&path, Some(Format::Cbor))
+            .save(&replica)
+            .unwrap();
+
+        let mut store = FileStore::new(&path, None);
+        store.load().unwrap();
+        replica.add_task("walk dog".into());
+        store.save(&replica).unwrap();
+
+        assert_eq!(Format::detect(&std::fs::read(&path).unwrap()), Format::Cbor);
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{Result, bail};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The tag CBOR files start with. It's CBOR's "self-described CBOR" tag, so
+/// CBOR tools read the files fine, and no JSON file starts with it.
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// How a replica (or changes exported from one) is written to a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Pretty-printed JSON, which is easy to read and diff.
+    #[default]
+    Json,
+
+    /// CBOR, which is much smaller: clocks are integers and raw bytes instead
+    /// of strings.
+    Cbor,
+}
+
+impl Format {
+    /// Which format a file starting with `bytes` is in. Anything that isn't
+    /// CBOR is JSON, since every file was before there was a choice.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&CBOR_MAGIC) {
+            Format::Cbor
+        } else {
+            Format::Json
+        }
+    }
+
+    pub fn encode(self, mut writer: impl Write, value: &impl Serialize) -> Result<()> {
+        match self {
+            Format::Json => serde_json::to_writer_pretty(writer, value)?,
+            Format::Cbor => {
+                writer.write_all(&CBOR_MAGIC)?;
+                ciborium::into_writer(value, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            Format::Json => serde_json::from_slice(bytes)?,
+            Format::Cbor => {
+                ciborium::from_reader(bytes.strip_prefix(&CBOR_MAGIC).unwrap_or(bytes))?
+            }
+        })
+    }
+
+    /// Decode into a JSON value, whatever the format, so migrations only
+    /// have to deal with JSON. Replicas deserialize the same from either.
+    pub fn decode_value(self, bytes: &[u8]) -> Result<Value> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(bytes)?),
+            Format::Cbor => cbor_to_json(self.decode(bytes)?),
+        }
+    }
+}
+
+/// The JSON version of a CBOR value. Byte strings are UUIDs (the only thing
+/// we write as bytes), and map keys become strings.
+fn cbor_to_json(value: ciborium::Value) -> Result<Value> {
+    use ciborium::Value as Cbor;
+
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(bool) => Value::Bool(bool),
+        Cbor::Integer(integer) => {
+            let integer = i128::from(integer);
+            if let Ok(unsigned) = u64::try_from(integer) {
+                Value::from(unsigned)
+            } else if let Ok(signed) = i64::try_from(integer) {
+                Value::from(signed)
+            } else {
+                bail!("{integer} is too big for JSON")
+            }
+        }
+        Cbor::Float(float) => Value::from(float),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bytes(bytes) => match Uuid::from_slice(&bytes) {
+            Ok(uuid) => Value::String(uuid.to_string()),
+            Err(_) => Value::from(bytes),
+        },
+        Cbor::Tag(_, value) => cbor_to_json(*value)?,
+        Cbor::Array(values) => values
+            .into_iter()
+            .map(cbor_to_json)
+            .collect::<Result<_>>()?,
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match cbor_to_json(key)? {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+
+                    Ok((key, cbor_to_json(value)?))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        value => bail!("can't convert {value:?} to JSON"),
+    })
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Cbor => write!(f, "CBOR"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "cbor" => Ok(Format::Cbor),
+            _ => bail!("unknown format `{format}` (expected `json` or `cbor`)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replica::Replica;
+
+    /// A replica with a bit of everything in it.
+    fn replica() -> Replica {
+        let mut replica = Replica::new();
+        let walk = replica.add_task("walk dog".into());
+        let milk = replica.add_task("buy milk".into());
+        replica.edit_task_description(&walk, "walk the dog");
+        replica.nest_task(&milk, Some(&walk));
+        replica.move_task(&milk, Some(&walk));
+        replica.complete_task(&milk);
+        replica.archive_completed_tasks();
+
+        replica
+    }
+
+    #[test]
+    fn round_trips_are_lossless() {
+        let replica = replica();
+        let json = serde_json::to_value(&replica).unwrap();
+
+        for format in [Format::Json, Format::Cbor] {
+            let mut bytes = Vec::new();
+            format.encode(&mut bytes, &replica).unwrap();
+
+            assert_eq!(Format::detect(&bytes), format);
+            let decoded: Replica = format.decode(&bytes).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json, "{format}");
+        }
+    }
+
+    #[test]
+    fn cbor_values_decode_like_json() {
+        let replica = replica();
+        let json = serde_json::to_value(&replica).unwrap();
+
+        let mut cbor = Vec::new();
+        Format::Cbor.encode(&mut cbor, &replica).unwrap();
+        let value = Format::Cbor.decode_value(&cbor).unwrap();
+
+        let decoded: Replica = serde_json::from_value(value).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+    }
+
+    #[test]
+    fn cbor_is_smaller() {
+        let replica = replica();
+
+        let mut json = Vec::new();
+        Format::Json.encode(&mut json, &replica).unwrap();
+        let mut cbor = Vec::new();
+        Format::Cbor.encode(&mut cbor, &replica).unwrap();
+
+        assert!(
+            cbor.len() * 2 < json.len(),
+            "{} vs {}",
+            cbor.len(),
+            json.len()
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use super::migration::CURRENT_VERSION;
+use super::{FileStore, Format, Store, sibling};
+use crate::crdt::HybridLogicalClock;
+use crate::replica::{Operation, Replica};
+use anyhow::{Context, Result, bail};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A snapshot of the replica, plus a journal next to it (at
+/// `<path>.journal`) of the operations made since the snapshot, one JSON
+/// record per line, each with the version it's in. Commands that only make
+/// operations append them to the journal. The snapshot is only rewritten once
+/// the journal grows past `limit` bytes, or when a command changed the
+/// replica some other way (like merging).
+#[derive(Debug, Clone)]
+pub struct JournalStore {
+    snapshot: PathBuf,
+    journal: PathBuf,
+    format: Option<Format>,
+    limit: u64,
+
+    /// How many bytes at the start of the journal hold whole records, or
+    /// `None` if we haven't read it. Anything after that is a record that
+    /// was cut off partway through being written.
+    records: Option<u64>,
+
+    /// The newest of the replica's unsaved operations already stored.
+    saved: Option<HybridLogicalClock>,
+
+    /// The oldest version of the snapshot and the records in the journal.
+    version: Option<u32>,
+}
+
+/// A line of the journal: an operation and the layout version it's in.
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    version: u32,
+    operation: &'a Operation,
+}
+
+/// A line of the journal as it's read. Records from before they had versions
+/// are just the operation, in the snapshot's version (writing a snapshot
+/// clears the journal, so they can't be from an older one).
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StoredRecord {
+    Versioned { version: u32, operation: Operation },
+    Bare(Operation),
+}
+
+impl JournalStore {
+    /// A store with its snapshot at `path`, which is written in `format`
+    /// (or whatever format it's already in, if that's `None`).
+    pub fn new(path: &Path, format: Option<Format>, limit: u64) -> Self {
+        JournalStore {
+            snapshot: path.to_path_buf(),
+            journal: sibling(path, "journal"),
+            format,
+            limit,
+            records: None,
+            saved: None,
+            version: None,
+        }
+    }
+
+    /// Read the records in the journal, ignoring a final one that didn't
+    /// finish being written.
+    fn read_journal(&self) -> Result<(Vec<StoredRecord>, u64)> {
+        let journal = match std::fs::read(&self.journal) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("could not read `{}`", self.journal.display()));
+            }
+        };
+
+        let end = journal.len() as u64;
+        let mut stored = Vec::new();
+        let mut records = 0;
+        for line in journal.split_inclusive(|byte| *byte == b'\n') {
+            match line.strip_suffix(b"\n").map(serde_json::from_slice) {
+                Some(Ok(record)) => {
+                    stored.push(record);
+                    records += line.len() as u64;
+                }
+                // A crash while appending can leave the last record cut off.
+                _ if records + line.len() as u64 == end => {
+                    tracing::warn!("ignoring a torn record at the end of the journal");
+                }
+                Some(Err(err)) => {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "could not read record {} in `{}`",
+                            stored.len() + 1,
+                            self.journal.display()
+                        )
+                    });
+                }
+                None => unreachable!("only the last line can be missing its newline"),
+            }
+        }
+
+        Ok((stored, records))
+    }
+
+    fn write_snapshot(&mut self, replica: &Replica) -> Result<()> {
+        let mut snapshot = FileStore::new(&self.snapshot, self.format);
+        snapshot.save(replica)?;
+
+        // If we crash before this, the journal's operations are all in the
+        // snapshot already, and replaying them does nothing.
+        File::create(&self.journal)
+            .and_then(|journal| journal.sync_all())
+            .with_context(|| format!("could not clear `{}`", self.journal.display()))?;
+
+        self.records = Some(0);
+        self.saved = last_clock(replica);
+        self.version = snapshot.version();
+        self.format = snapshot.format();
+
+        Ok(())
+    }
+}
+
+fn last_clock(replica: &Replica) -> Option<HybridLogicalClock> {
+    replica
+        .unsaved_operations()
+        .and_then(|operations| operations.last())
+        .map(|operation| operation.clock)
+}
+
+impl Store for JournalStore {
+    #[tracing::instrument(name = "JournalStore::load", skip(self))]
+    fn load(&mut self) -> Result<Option<Replica>> {
+        let (stored, records) = self.read_journal()?;
+
+        let mut snapshot = FileStore::new(&self.snapshot, self.format);
+        let Some(mut replica) = snapshot.load()? else {
+            if !stored.is_empty() {
+                bail!(
+                    "`{}` has operations but there's no snapshot to apply them to",
+                    self.journal.display()
+                );
+            }
+
+            return Ok(None);
+        };
+
+        let snapshot_version = snapshot.version().unwrap_or(0);
+        let mut version = snapshot_version;
+        for record in stored {
+            let (record_version, operation) = match record {
+                StoredRecord::Versioned { version, operation } => (version, operation),
+                StoredRecord::Bare(operation) => (snapshot_version, operation),
+            };
+            if record_version > CURRENT_VERSION {
+                bail!(
+                    "`{}` has operations in version {record_version}, but this program only understands up to version {CURRENT_VERSION}",
+                    self.journal.display()
+                );
+            }
+
+            // Operations haven't changed in a way the current ones can't
+            // read yet. Once they do, older records need migrating here.
+            version = version.min(record_version);
+            replica.replay(operation);
+        }
+
+        self.records = Some(records);
+        self.saved = None;
+        self.version = Some(version);
+        self.format = snapshot.format();
+
+        Ok(Some(replica))
+    }
+
+    #[tracing::instrument(name = "JournalStore::save", skip(self, replica))]
+    fn save(&mut self, replica: &Replica) -> Result<()> {
+        self.write_snapshot(replica)
+    }
+
+    #[tracing::instrument(name = "JournalStore::update", skip(self, replica))]
+    fn update(&mut self, replica: &Replica) -> Result<()> {
+        let (Some(records), Some(operations)) = (self.records, replica.unsaved_operations()) else {
+            return self.write_snapshot(replica);
+        };
+
+        let mut appended = Vec::new();
+        for operation in operations
+            .iter()
+            .filter(|operation| self.saved.is_none_or(|saved| operation.clock > saved))
+        {
+            let record = Record {
+                version: CURRENT_VERSION,
+                operation,
+            };
+            serde_json::to_writer(&mut appended, &record)?;
+            appended.push(b'\n');
+        }
+
+        if appended.is_empty() {
+            return Ok(());
+        }
+
+        if records + appended.len() as u64 > self.limit {
+            return self.write_snapshot(replica);
+        }
+
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.journal)
+            .with_context(|| format!("could not open `{}`", self.journal.display()))?;
+
+        // Cut off any torn record so the new ones start on a line of their
+        // own.
+        journal
+            .set_len(records)
+            .and_then(|_| journal.seek(SeekFrom::End(0)))
+            .and_then(|_| journal.write_all(&appended))
+            .and_then(|_| journal.sync_data())
+            .with_context(|| format!("could not append to `{}`", self.journal.display()))?;
+
+        self.records = Some(records + appended.len() as u64);
+        self.saved = last_clock(replica);
+
+        Ok(())
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::{DEFAULT_JOURNAL_LIMIT, TempDir};
+
+    /// A store in a directory of its own, which goes away with the returned
+    /// `TempDir`.
+    fn store(limit: u64) -> (TempDir, JournalStore) {
+        let dir = TempDir::new("journal");
+        let store = JournalStore::new(&dir.join("tasks.json"), Some(Format::Json), limit);
+
+        (dir, store)
+    }
+
+    fn reload(store: &JournalStore) -> Replica {
+        JournalStore::new(&store.snapshot, store.format, store.limit)
+            .load()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn json(replica: &Replica) -> serde_json::Value {
+        serde_json::to_value(replica).unwrap()
+    }
+
+    #[test]
+    fn appends_operations_to_the_journal() {
+        let (_dir, mut store) = store(DEFAULT_JOURNAL_LIMIT);
+        assert!(store.load().unwrap().is_none());
+
+        let mut replica = Replica::new();
+        replica.add_task("Buy milk".into());
+        store.update(&replica).unwrap();
+        let snapshot = std::fs::read(&store.snapshot).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        let id = replica.add_task("Walk dog".into());
+        store.update(&replica).unwrap();
+        replica.complete_task(&id);
+        store.update(&replica).unwrap();
+
+        assert_eq!(std::fs::read(&store.snapshot).unwrap(), snapshot);
+        let journal = std::fs::read_to_string(&store.journal).unwrap();
+        assert_eq!(journal.lines().count(), 2);
+
+        assert_eq!(json(&reload(&store)), json(&replica));
+    }
+
+    #[test]
+    fn records_are_in_the_oldest_version_they_or_the_snapshot_are_in() {
+        let (_dir, mut store) = store(DEFAULT_JOURNAL_LIMIT);
+        store.save(&Replica::new()).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        replica.add_task("Buy milk".into());
+        store.update(&replica).unwrap();
+
+        let journal = std::fs::read(&store.journal).unwrap();
+        let record: serde_json::Value = serde_json::from_slice(&journal).unwrap();
+        assert_eq!(record["version"], CURRENT_VERSION);
+
+        let mut old = record.clone();
+        old["version"] = 0.into();
+        std::fs::write(&store.journal, format!("{old}\n{}\n", record["operation"])).unwrap();
+
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&replica));
+        assert_eq!(store.version(), Some(0));
+
+        let mut newer = record;
+        newer["version"] = (CURRENT_VERSION + 1).into();
+        std::fs::write(&store.journal, format!("{newer}\n")).unwrap();
+        assert!(store.load().is_err());
+    }
+
+    #[test]
+    fn ignores_a_torn_record_at_the_end() {
+        let (_dir, mut store) = store(DEFAULT_JOURNAL_LIMIT);
+        store.save(&Replica::new()).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        replica.add_task("Buy milk".into());
+        store.update(&replica).unwrap();
+        let before = reload(&store);
+
+        replica.add_task("Walk dog".into());
+        store.update(&replica).unwrap();
+        let journal = std::fs::read(&store.journal).unwrap();
+        std::fs::write(&store.journal, &journal[..journal.len() - 10]).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        assert_eq!(json(&replica), json(&before));
+
+        // New records replace the torn one.
+        replica.add_task("Feed cat".into());
+        store.update(&replica).unwrap();
+        assert_eq!(json(&reload(&store)), json(&replica));
+    }
+
+    #[test]
+    fn rewrites_the_snapshot_once_the_journal_is_full() {
+        let (_dir, mut store) = store(1);
+        store.save(&Replica::new()).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        replica.add_task("Buy milk".into());
+        store.update(&replica).unwrap();
+
+        assert_eq!(std::fs::read(&store.journal).unwrap(), b"");
+        assert_eq!(json(&reload(&store)), json(&replica));
+    }
+
+    #[test]
+    fn rewrites_the_snapshot_after_a_merge() {
+        let (_dir, mut store) = store(DEFAULT_JOURNAL_LIMIT);
+        store.save(&Replica::new()).unwrap();
+
+        let mut other = Replica::new();
+        other.add_task("Buy milk".into());
+
+        let mut replica = store.load().unwrap().unwrap();
+        replica.receive(other).unwrap();
+        store.update(&replica).unwrap();
+
+        assert_eq!(std::fs::read(&store.journal).unwrap(), b"");
+        assert_eq!(json(&reload(&store)), json(&replica));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::crdt::HybridLogicalClock;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeSet;
+
+/// The version of the layout replicas are stored in. Whenever a change to
+/// `Replica` (or anything in it) means replicas stored before won't load,
+/// bump this and add a migration from the old version to `MIGRATIONS`.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// A stored replica, along with the version of the layout it's in. Replicas
+/// stored before there were versions aren't wrapped in one, and are
+/// version 0.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    pub replica: T,
+}
+
+/// Changes a replica stored in version `from` to the layout of the version
+/// after it.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub migrate: fn(&mut Value) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "keep tasks in an observed-remove map with multi-value descriptions",
+    migrate: migrate_v0,
+}];
+
+/// Version 0 is everything from before replicas were versioned, which
+/// started out keeping tasks in a two-phase map (`adds` and `removes`) with
+/// each description in a last-writer-wins register. Later version 0 replicas
+/// already have some or all of the version 1 layout, so each change is only
+/// made where it's needed. Completion is still read from a last-writer-wins
+/// register as it is (see `Task`), so it isn't touched.
+fn migrate_v0(replica: &mut Value) -> Result<()> {
+    if replica.pointer("/document/tasks").is_none() {
+        bail!("the replica has no tasks");
+    }
+
+    migrate_v0_documents(replica)
+}
+
+/// Migrate every document in `value`: the replica's own, and the ones its
+/// operations carry.
+fn migrate_v0_documents(value: &mut Value) -> Result<()> {
+    match value {
+        Value::Object(fields) => match fields.get_mut("tasks") {
+            Some(tasks) => {
+                let archived = migrate_v0_tasks(tasks)?;
+                if !archived.is_empty() {
+                    fields.insert("legacy_removes".into(), json!(archived));
+                }
+
+                Ok(())
+            }
+            None => fields.values_mut().try_for_each(migrate_v0_documents),
+        },
+        Value::Array(values) => values.iter_mut().try_for_each(migrate_v0_documents),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the IDs of tasks that were archived before the migration and left
+/// nothing but their ID behind (see `two_phase_to_entries`).
+fn migrate_v0_tasks(tasks: &mut Value) -> Result<Vec<String>> {
+    let mut archived = Vec::new();
+    if let Some(adds) = tasks.get_mut("adds") {
+        let entries;
+        (entries, archived) = two_phase_to_entries(adds.take(), &tasks["removes"])?;
+        *tasks = json!({ "entries": entries });
+    }
+
+    if let Some(Value::Object(entries)) = tasks.get_mut("entries") {
+        for entry in entries.values_mut() {
+            if let Some(description) = entry.pointer_mut("/value/description") {
+                lww_to_mv(description);
+            }
+        }
+    }
+
+    Ok(archived)
+}
+
+/// Entries of an observed-remove map for the tasks in a two-phase map's
+/// `adds`: added at the clock they were added at and, if they're in
+/// `removes` too, removed at their newest clock. Archiving used to drop tasks
+/// from `adds`, which leaves no clock to remove them at, so their IDs are
+/// returned alongside the entries for the document to keep as tombstones.
+fn two_phase_to_entries(adds: Value, removes: &Value) -> Result<(Map<String, Value>, Vec<String>)> {
+    let Value::Object(adds) = adds else {
+        bail!("tasks have to be a map from ID to task");
+    };
+    let removes: BTreeSet<&str> = removes
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+
+    let mut entries = Map::new();
+    for (id, task) in adds {
+        let added = task
+            .pointer("/added/clock")
+            .with_context(|| format!("task {id} has no clock for when it was added"))?
+            .clone();
+
+        let (removes, removed_at) = if removes.contains(id.as_str()) {
+            let newest = newest_clock(&task).with_context(|| format!("task {id}"))?;
+            (json!([added]), json!([newest]))
+        } else {
+            (json!([]), json!([]))
+        };
+
+        let entry = json!({
+            "value": task,
+            "adds": [added],
+            "removes": removes,
+            "removed_at": removed_at,
+        });
+        entries.insert(id, entry);
+    }
+
+    let archived = removes
+        .into_iter()
+        .filter(|id| !entries.contains_key(*id))
+        .map(str::to_string)
+        .collect();
+
+    Ok((entries, archived))
+}
+
+/// Rewrite a last-writer-wins register (`value` and `clock`) as a
+/// multi-value register holding that one value. Anything else is left alone.
+fn lww_to_mv(register: &mut Value) {
+    let (Some(value), Some(clock)) = (register.get("value"), register.get("clock")) else {
+        return;
+    };
+
+    *register = json!({
+        "values": [[clock, value]],
+        "context": [clock],
+    });
+}
+
+/// The newest clock anywhere in `value`.
+fn newest_clock(value: &Value) -> Result<Value> {
+    fn each_clock<'a>(value: &'a Value, clocks: &mut Vec<&'a Value>) {
+        match value {
+            Value::Object(fields) if fields.contains_key("node_id") => clocks.push(value),
+            Value::Object(fields) => fields.values().for_each(|value| each_clock(value, clocks)),
+            Value::Array(values) => values.iter().for_each(|value| each_clock(value, clocks)),
+            _ => {}
+        }
+    }
+
+    let mut clocks = Vec::new();
+    each_clock(value, &mut clocks);
+
+    let mut newest: Option<(HybridLogicalClock, &Value)> = None;
+    for clock in clocks {
+        let parsed = HybridLogicalClock::deserialize(clock)?;
+        if newest.is_none_or(|(newest, _)| parsed > newest) {
+            newest = Some((parsed, clock));
+        }
+    }
+
+    newest
+        .map(|(_, clock)| clock.clone())
+        .context("there are no clocks in it")
+}
+
+/// Bring a replica stored in `version` up to `CURRENT_VERSION`.
+pub fn migrate(replica: Value, version: u32) -> Result<Value> {
+    migrate_with(MIGRATIONS, CURRENT_VERSION, replica, version)
+}
+
+fn migrate_with(
+    migrations: &[Migration],
+    current: u32,
+    mut replica: Value,
+    version: u32,
+) -> Result<Value> {
+    if version > current {
+        bail!(
+            "the replica is stored in version {version}, but this program only understands up to version {current}"
+        );
+    }
+
+    for version in version..current {
+        let Some(migration) = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+        else {
+            bail!("there's no migration from version {version}");
+        };
+
+        (migration.migrate)(&mut replica).with_context(|| {
+            format!(
+                "could not migrate from version {version} ({})",
+                migration.description
+            )
+        })?;
+    }
+
+    Ok(replica)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const RENAMES: &[Migration] = &[
+        Migration {
+            from: 0,
+            description: "rename a to b",
+            migrate: |replica| {
+                replica["b"] = replica["a"].take();
+                Ok(())
+            },
+        },
+        Migration {
+            from: 1,
+            description: "rename b to c",
+            migrate: |replica| {
+                replica["c"] = replica["b"].take();
+                Ok(())
+            },
+        },
+    ];
+
+    #[test]
+    fn runs_every_migration_after_the_stored_version() {
+        let migrated = migrate_with(RENAMES, 2, json!({"a": 1}), 0).unwrap();
+        assert_eq!(migrated["c"], 1);
+
+        let migrated = migrate_with(RENAMES, 2, json!({"b": 1}), 1).unwrap();
+        assert_eq!(migrated["c"], 1);
+    }
+
+    #[test]
+    fn refuses_versions_it_does_not_know() {
+        assert!(migrate_with(RENAMES, 2, json!({}), 3).is_err());
+        assert!(migrate_with(RENAMES, 3, json!({}), 0).is_err());
+    }
+
+    #[test]
+    fn version_zero_removes_tasks_at_their_newest_clock() {
+        let clock = |timestamp| {
+            json!({
+                "timestamp": timestamp,
+                "counter": 0,
+                "node_id": "6e62ed9c-bb64-44ef-9621-9d558985ed69",
+            })
+        };
+        let added = clock("2026-10-18T06:35:47Z");
+        let completed = clock("2026-10-18T06:35:49Z");
+
+        let mut replica = json!({
+            "document": {
+                "tasks": {
+                    "adds": {
+                        "feed cat": {
+                            "added": { "value": "2026-10-18T06:35:47Z", "clock": added },
+                            "complete": { "value": true, "clock": completed },
+                            "description": { "value": "feed cat", "clock": added },
+                        },
+                    },
+                    "removes": ["feed cat", "walk dog"],
+                },
+            },
+        });
+        migrate_v0(&mut replica).unwrap();
+        assert_eq!(replica["document"]["legacy_removes"], json!(["walk dog"]));
+
+        let entry = &replica["document"]["tasks"]["entries"]["feed cat"];
+        assert_eq!(entry["adds"], json!([added]));
+        assert_eq!(entry["removes"], json!([added]));
+        assert_eq!(entry["removed_at"], json!([completed]));
+        assert_eq!(
+            entry["value"]["description"],
+            json!({ "values": [[added, "feed cat"]], "context": [added] })
+        );
+    }
+
+    #[test]
+    fn later_version_zero_layouts_are_left_alone() {
+        let description = json!({ "values": [], "context": [] });
+        let tasks = json!({ "entries": { "x": { "value": { "description": description } } } });
+        let mut replica = json!({ "document": { "tasks": tasks } });
+
+        migrate_v0(&mut replica).unwrap();
+        assert_eq!(replica["document"]["tasks"], tasks);
+        assert!(replica["document"].get("legacy_removes").is_none());
+    }
+
+    #[test]
+    fn every_older_version_has_a_migration() {
+        for version in 0..CURRENT_VERSION {
+            assert!(
+                MIGRATIONS.iter().any(|migration| migration.from == version),
+                "no migration from {version}"
+            );
+        }
+    }
+}
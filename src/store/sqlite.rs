@@ -0,0 +1,438 @@
+use super::Store;
+use super::migration::{CURRENT_VERSION, migrate};
+use crate::crdt::HybridLogicalClock;
+use crate::replica::Replica;
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A part of a serialized replica that grows with it, kept in a table of its
+/// own with a row for each element.
+struct Table {
+    name: &'static str,
+
+    /// Where the elements are in a serialized replica.
+    pointer: &'static str,
+
+    layout: Layout,
+}
+
+enum Layout {
+    /// An object, with a row for each field holding its value.
+    Map,
+
+    /// An array whose order doesn't matter. Each element is the ID of its
+    /// row, and the value is empty.
+    Set,
+
+    /// An array of operations, stored like a `Set` and put back in the order
+    /// of their clocks, which is an order they can be applied in.
+    Log,
+}
+
+const TABLES: [Table; 4] = [
+    Table {
+        name: "tasks",
+        pointer: "/document/tasks/entries",
+        layout: Layout::Map,
+    },
+    Table {
+        name: "order_inserts",
+        pointer: "/document/order/inserts",
+        layout: Layout::Set,
+    },
+    Table {
+        name: "order_removes",
+        pointer: "/document/order/removes",
+        layout: Layout::Set,
+    },
+    Table {
+        name: "operations",
+        pointer: "/log/operations",
+        layout: Layout::Log,
+    },
+];
+
+/// The replica in a SQLite database. Each task, element of the task order,
+/// and logged operation is stored in its own row and everything else in one
+/// more, so `update` only rewrites the parts that changed instead of the
+/// whole replica.
+pub struct SqliteStore {
+    connection: Connection,
+
+    /// What the database holds as of the last load or save, or `None` if we
+    /// don't know.
+    stored: Option<Rows>,
+
+    /// The layout version the rows are in, which is kept in the database's
+    /// `user_version`. Databases from before there were versions have 0.
+    version: Option<u32>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Rows {
+    replica: String,
+
+    /// The rows of each of `TABLES`, by name and then ID.
+    tables: BTreeMap<&'static str, BTreeMap<String, String>>,
+}
+
+impl Rows {
+    fn new(replica: &Replica) -> Result<Self> {
+        let mut replica = serde_json::to_value(replica)?;
+
+        let mut tables = BTreeMap::new();
+        for table in &TABLES {
+            let rows = match replica.pointer_mut(table.pointer) {
+                Some(Value::Object(fields)) => std::mem::take(fields)
+                    .into_iter()
+                    .map(|(id, value)| (id, value.to_string()))
+                    .collect(),
+                Some(Value::Array(elements)) => std::mem::take(elements)
+                    .into_iter()
+                    .map(|element| (element.to_string(), String::new()))
+                    .collect(),
+                _ => BTreeMap::new(),
+            };
+            tables.insert(table.name, rows);
+        }
+
+        Ok(Rows {
+            replica: replica.to_string(),
+            tables,
+        })
+    }
+
+    fn to_replica(&self, version: u32) -> Result<Replica> {
+        let mut replica: Value = serde_json::from_str(&self.replica)?;
+
+        for table in &TABLES {
+            let Some(rows) = self.tables.get(table.name).filter(|rows| !rows.is_empty()) else {
+                continue;
+            };
+            let parse = |json: &str| -> Result<Value> {
+                serde_json::from_str(json).with_context(|| format!("row in {}", table.name))
+            };
+
+            match (replica.pointer_mut(table.pointer), &table.layout) {
+                (Some(Value::Object(fields)), Layout::Map) => {
+                    for (id, value) in rows {
+                        fields.insert(id.clone(), parse(value)?);
+                    }
+                }
+                (Some(Value::Array(elements)), Layout::Set) => {
+                    for id in rows.keys() {
+                        elements.push(parse(id)?);
+                    }
+                }
+                (Some(Value::Array(elements)), Layout::Log) => {
+                    let mut operations = Vec::new();
+                    for id in rows.keys() {
+                        let operation = parse(id)?;
+                        let clock: HybridLogicalClock =
+                            serde_json::from_value(operation["clock"].clone())
+                                .with_context(|| format!("clock of operation {id}"))?;
+                        operations.push((clock, operation));
+                    }
+
+                    operations.sort_by_key(|(clock, _)| *clock);
+                    elements.extend(operations.into_iter().map(|(_, operation)| operation));
+                }
+                _ => bail!("stored replica has no place for {}", table.name),
+            }
+        }
+
+        Ok(serde_json::from_value(migrate(replica, version)?)?)
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("could not open `{}`", path.display()))?;
+
+        Self::new(connection)
+    }
+
+    fn new(connection: Connection) -> Result<Self> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS replica (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    value TEXT NOT NULL
+                );",
+            )
+            .context("could not create tables")?;
+        for table in &TABLES {
+            connection
+                .execute_batch(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        id TEXT PRIMARY KEY,
+                        value TEXT NOT NULL
+                    );",
+                    table.name
+                ))
+                .with_context(|| format!("could not create the {} table", table.name))?;
+        }
+
+        Ok(SqliteStore {
+            connection,
+            stored: None,
+            version: None,
+        })
+    }
+
+    /// Write the rows for `replica` that differ from `stored`, or all of them
+    /// (after clearing the tables) if we don't know what's stored.
+    fn write(&mut self, replica: &Replica) -> Result<()> {
+        let rows = Rows::new(replica)?;
+        let stored = self.stored.take();
+
+        let transaction = self.connection.transaction()?;
+        let stored = match stored {
+            Some(stored) => stored,
+            None => {
+                transaction.execute("DELETE FROM replica", [])?;
+                for table in &TABLES {
+                    transaction.execute(&format!("DELETE FROM {}", table.name), [])?;
+                }
+                Rows::default()
+            }
+        };
+
+        if rows.replica != stored.replica {
+            transaction.execute(
+                "INSERT INTO replica (id, value) VALUES (0, ?1)
+                ON CONFLICT (id) DO UPDATE SET value = excluded.value",
+                [&rows.replica],
+            )?;
+        }
+
+        let none = BTreeMap::new();
+        for table in &TABLES {
+            let new = rows.tables.get(table.name).unwrap_or(&none);
+            let old = stored.tables.get(table.name).unwrap_or(&none);
+
+            let mut upsert = transaction.prepare_cached(&format!(
+                "INSERT INTO {} (id, value) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET value = excluded.value",
+                table.name
+            ))?;
+            for (id, value) in new {
+                if old.get(id) != Some(value) {
+                    upsert.execute([id, value])?;
+                }
+            }
+            drop(upsert);
+
+            let mut delete =
+                transaction.prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table.name))?;
+            for id in old.keys() {
+                if !new.contains_key(id) {
+                    delete.execute([id])?;
+                }
+            }
+        }
+
+        if self.version != Some(CURRENT_VERSION) {
+            transaction.pragma_update(None, "user_version", CURRENT_VERSION)?;
+        }
+
+        transaction.commit()?;
+        self.stored = Some(rows);
+        self.version = Some(CURRENT_VERSION);
+
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    #[tracing::instrument(name = "SqliteStore::load", skip(self))]
+    fn load(&mut self) -> Result<Option<Replica>> {
+        let Some(replica) = self
+            .connection
+            .query_row("SELECT value FROM replica", [], |row| row.get(0))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut tables = BTreeMap::new();
+        for table in &TABLES {
+            let rows = self
+                .connection
+                .prepare(&format!("SELECT id, value FROM {}", table.name))?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            tables.insert(table.name, rows);
+        }
+
+        let version = self
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        let rows = Rows { replica, tables };
+        let replica = rows
+            .to_replica(version)
+            .context("could not read the stored replica")?;
+        self.stored = Some(rows);
+        self.version = Some(version);
+
+        Ok(Some(replica))
+    }
+
+    #[tracing::instrument(name = "SqliteStore::save", skip(self, replica))]
+    fn save(&mut self, replica: &Replica) -> Result<()> {
+        self.stored = None;
+        self.write(replica)
+    }
+
+    #[tracing::instrument(name = "SqliteStore::update", skip(self, replica))]
+    fn update(&mut self, replica: &Replica) -> Result<()> {
+        self.write(replica)
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdt::hlc::WallClock;
+    use crate::ids::SeededIds;
+    use std::sync::Arc;
+
+    fn store() -> SqliteStore {
+        SqliteStore::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn json(replica: &Replica) -> Value {
+        serde_json::to_value(replica).unwrap()
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let mut replica = Replica::new();
+        replica.add_task("walk dog".into());
+        replica.add_task("buy milk".into());
+
+        let mut store = store();
+        assert!(store.load().unwrap().is_none());
+
+        store.save(&replica).unwrap();
+        store.stored = None;
+
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&replica));
+    }
+
+    #[test]
+    fn update_only_writes_changed_tasks() {
+        let mut replica = Replica::new();
+        let ids: Vec<_> = (0..3)
+            .map(|n| replica.add_task(format!("task {n}")))
+            .collect();
+
+        let mut store = store();
+        store.save(&replica).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        replica.complete_task(&ids[1]);
+
+        let before = store.connection.total_changes();
+        store.update(&replica).unwrap();
+
+        // The completed task and the rest of the replica.
+        assert_eq!(store.connection.total_changes() - before, 2);
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&replica));
+    }
+
+    #[test]
+    fn a_new_replica_only_writes_its_own_tasks() {
+        let replica =
+            |seed| Replica::with_sources(Arc::new(WallClock), Arc::new(SeededIds::new(seed)));
+        let (mut first, mut second) = (replica(1), replica(2));
+        // The newcomer sorts first, so it would take every other replica's
+        // place in a sorted node table.
+        if first.id() < second.id() {
+            std::mem::swap(&mut first, &mut second);
+        }
+
+        for n in 0..3 {
+            first.add_task(format!("task {n}"));
+        }
+
+        let mut store = store();
+        store.save(&first).unwrap();
+
+        let mut replica = store.load().unwrap().unwrap();
+        second.add_task("walk dog".into());
+        replica.receive(second).unwrap();
+
+        let before = store.connection.total_changes();
+        store.update(&replica).unwrap();
+
+        // The new task, its place in the order, and the rest of the replica.
+        assert_eq!(store.connection.total_changes() - before, 3);
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&replica));
+    }
+
+    #[test]
+    fn operations_are_written_once_and_load_in_order() {
+        let mut replica = Replica::new();
+        replica.receive(Replica::new()).unwrap();
+        let id = replica.add_task("walk dog".into());
+
+        let mut store = store();
+        store.save(&replica).unwrap();
+
+        replica.complete_task(&id);
+        replica.update_task_description(&id, "walk the dog".into());
+        assert_eq!(replica.operations().count(), 3);
+
+        let before = store.connection.total_changes();
+        store.update(&replica).unwrap();
+
+        // The two new operations, the task they changed, and the rest of the
+        // replica.
+        assert_eq!(store.connection.total_changes() - before, 4);
+
+        store.stored = None;
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&replica));
+    }
+
+    #[test]
+    fn save_replaces_tasks_that_are_gone() {
+        let mut first = Replica::new();
+        first.add_task("walk dog".into());
+
+        let mut store = store();
+        store.save(&first).unwrap();
+
+        let mut second = Replica::new();
+        second.add_task("buy milk".into());
+        store.save(&second).unwrap();
+
+        assert_eq!(json(&store.load().unwrap().unwrap()), json(&second));
+    }
+
+    #[test]
+    fn records_the_version_it_saved() {
+        let mut store = store();
+        store.save(&Replica::new()).unwrap();
+
+        store
+            .connection
+            .pragma_update(None, "user_version", 0)
+            .unwrap();
+        store.load().unwrap().unwrap();
+        assert_eq!(store.version(), Some(0));
+
+        store.save(&Replica::new()).unwrap();
+        store.load().unwrap().unwrap();
+        assert_eq!(store.version(), Some(CURRENT_VERSION));
+    }
+}
@@ -0,0 +1,6 @@
+//! Checks the errors `#[derive(Merge)]` gives point at the right code.
+
+#[test]
+fn errors_point_at_the_problem() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
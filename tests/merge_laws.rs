@@ -0,0 +1,13 @@
+//! A CRDT built outside this crate, checked with the law tests the
+//! `testing` feature exposes.
+
+use proptest_derive::Arbitrary;
+use rust_crdt_talk::crdt::{EWFlag, GCounter, Merge};
+
+#[derive(Debug, Clone, PartialEq, Merge, Arbitrary)]
+struct Page {
+    views: GCounter,
+    pinned: Option<EWFlag>,
+}
+
+rust_crdt_talk::merge_laws!(page_laws, Page);
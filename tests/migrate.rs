@@ -0,0 +1,76 @@
+//! Replicas stored before there were versions, as the first release of the
+//! program wrote them.
+
+use rust_crdt_talk::store::{CURRENT_VERSION, MIGRATIONS, decode_replica};
+use serde_json::Value;
+use uuid::{Uuid, uuid};
+
+/// Two tasks, one with an edited description and one completed, plus one
+/// that was completed and archived.
+const V0: &[u8] = include_bytes!("fixtures/v0.json");
+
+const WALK_DOG: Uuid = uuid!("df0eecac-a235-43c0-a964-fca872fece04");
+const BUY_MILK: Uuid = uuid!("3c25dc1e-2edb-44cb-ac95-dbdae5cc1fb5");
+const FEED_CAT: Uuid = uuid!("c0b20f83-860e-45bb-b03f-d0257cda43db");
+
+#[test]
+fn version_zero_migrates_to_the_current_layout() {
+    let mut replica: Value = serde_json::from_slice(V0).unwrap();
+    for migration in MIGRATIONS {
+        (migration.migrate)(&mut replica).unwrap();
+    }
+
+    let tasks = &replica["document"]["tasks"];
+    assert!(tasks.get("adds").is_none(), "{tasks}");
+
+    let walk_dog = &tasks["entries"][WALK_DOG.to_string()];
+    assert_eq!(walk_dog["removes"], serde_json::json!([]));
+    assert_eq!(
+        walk_dog["value"]["description"]["values"][0][1],
+        "walk the dog"
+    );
+}
+
+#[test]
+fn version_zero_loads() {
+    let (replica, version) = decode_replica(V0).unwrap();
+    assert_eq!(version, 0);
+    assert!(version < CURRENT_VERSION);
+
+    let tasks: Vec<(Uuid, String, bool)> = replica
+        .tasks()
+        .map(|(id, task)| (*id, task.description(), task.complete.value()))
+        .collect();
+    assert_eq!(
+        tasks,
+        [
+            (WALK_DOG, "walk the dog".to_string(), false),
+            (BUY_MILK, "buy milk".to_string(), true),
+        ]
+    );
+
+    assert!(replica.task(&FEED_CAT).is_none());
+}
+
+#[test]
+fn tasks_archived_in_version_zero_stay_archived() {
+    let (mut replica, _) = decode_replica(V0).unwrap();
+
+    // Another replica that synced before FEED_CAT was archived, and hasn't
+    // since.
+    let mut stale: Value = serde_json::from_slice(V0).unwrap();
+    stale["id"] = Uuid::from_u128(1).to_string().into();
+    let tasks = &mut stale["document"]["tasks"];
+    let mut feed_cat = tasks["adds"][BUY_MILK.to_string()].clone();
+    feed_cat["description"]["value"] = "feed the cat".into();
+    feed_cat["complete"]["value"] = false.into();
+    tasks["adds"][FEED_CAT.to_string()] = feed_cat;
+    tasks["removes"] = serde_json::json!([]);
+
+    let (stale, _) = decode_replica(&serde_json::to_vec(&stale).unwrap()).unwrap();
+    assert!(stale.task(&FEED_CAT).is_some());
+
+    replica.receive(stale).unwrap();
+    assert!(replica.task(&FEED_CAT).is_none());
+    assert!(replica.task(&BUY_MILK).is_some());
+}
@@ -0,0 +1,9 @@
+use rust_crdt_talk::crdt::Merge;
+
+#[derive(Merge)]
+enum Status {
+    Open,
+    Done,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use rust_crdt_talk::crdt::{GCounter, Merge};
+
+struct Label(String);
+
+#[derive(Merge)]
+struct Task {
+    views: GCounter,
+    label: Label,
+}
+
+fn main() {}